@@ -0,0 +1,125 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The filesystem operations the walk is scheduled by. With the `testing` feature they can be
+//! served from a synthetic tree instead, which makes the ordering logic testable without root or a specific filesystem.
+
+use btrfs::linux::FileExtent;
+use dir::{self, DirIter};
+use std::fs::FileType;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+#[cfg(any(test, feature = "testing"))]
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(any(test, feature = "testing"))]
+use std::path::PathBuf;
+
+pub struct Stat {
+    pub ftype: FileType,
+    pub ino: u64,
+    pub len: u64,
+}
+
+pub trait Backend {
+    fn read_dir(&self, base: Option<RawFd>, path: &Path, noatime: bool) -> io::Result<DirIter>;
+    fn stat(&self, base: Option<RawFd>, path: &Path, follow: bool) -> io::Result<Stat>;
+    fn extent_map(&self, base: Option<RawFd>, path: &Path) -> Result<Vec<FileExtent>, String>;
+}
+
+pub struct RealFs;
+
+impl Backend for RealFs {
+    fn read_dir(&self, base: Option<RawFd>, path: &Path, noatime: bool) -> io::Result<DirIter> {
+        DirIter::open(base, path, noatime)
+    }
+
+    fn stat(&self, base: Option<RawFd>, path: &Path, follow: bool) -> io::Result<Stat> {
+        let meta = if follow && base.is_none() {
+            ::std::fs::metadata(path)?
+        } else {
+            dir::symlink_metadata_at(base, path)?
+        };
+        Ok(Stat {
+            ftype: meta.file_type(),
+            ino: meta.ino(),
+            len: meta.len()
+        })
+    }
+
+    fn extent_map(&self, base: Option<RawFd>, path: &Path) -> Result<Vec<FileExtent>, String> {
+        dir::extent_map_at(base, path)
+    }
+}
+
+/// A synthetic tree of `path -> (file type, inode, extents)`, see `ToScan::with_fake_fs`.
+/// Every path's parent has to be present as a directory, except for the roots.
+/// File sizes are the sum of the extent lengths, symlinks are never followed.
+#[cfg(any(test, feature = "testing"))]
+pub struct FakeFs {
+    tree: BTreeMap<PathBuf, (FileType, u64, Vec<FileExtent>)>,
+    bad_entries: BTreeSet<PathBuf>,
+    replaced_dirs: BTreeSet<PathBuf>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl FakeFs {
+    pub fn new(tree: BTreeMap<PathBuf, (FileType, u64, Vec<FileExtent>)>) -> FakeFs {
+        FakeFs {
+            tree: tree,
+            bad_entries: BTreeSet::new(),
+            replaced_dirs: BTreeSet::new()
+        }
+    }
+
+    /// Listing the parent of `path` yields an EIO error in its place, like a corrupt directory entry
+    /// that `readdir` skips. `path` doesn't need to be part of the tree.
+    pub fn add_bad_entry(&mut self, path: PathBuf) {
+        self.bad_entries.insert(path);
+    }
+
+    /// `path` is still listed as a directory but reading it fails with ENOTDIR,
+    /// as if it had been replaced by a file after its parent was read
+    pub fn add_replaced_dir(&mut self, path: PathBuf) {
+        self.replaced_dirs.insert(path);
+    }
+
+    fn lookup(&self, path: &Path) -> io::Result<&(FileType, u64, Vec<FileExtent>)> {
+        self.tree.get(path).ok_or_else(|| io::Error::from_raw_os_error(::libc::ENOENT))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Backend for FakeFs {
+    fn read_dir(&self, _: Option<RawFd>, path: &Path, _: bool) -> io::Result<DirIter> {
+        if !self.lookup(path)?.0.is_dir() || self.replaced_dirs.contains(path) {
+            return Err(io::Error::from_raw_os_error(::libc::ENOTDIR));
+        }
+        let mut children: Vec<_> = self.tree.iter()
+            .filter(|&(p, _)| p.parent() == Some(path))
+            .map(|(p, &(ft, ino, _))| (p, Ok(dir::DirEntry::new(p.to_owned(), ino, ft))))
+            .chain(self.bad_entries.iter().filter(|p| p.parent() == Some(path)).map(|p| (p, Err(io::Error::from_raw_os_error(::libc::EIO)))))
+            .collect();
+        children.sort_by(|a, b| a.0.cmp(b.0));
+        Ok(DirIter::from_entries(children.into_iter().map(|(_, r)| r).collect()))
+    }
+
+    fn stat(&self, _: Option<RawFd>, path: &Path, _: bool) -> io::Result<Stat> {
+        let &(ft, ino, ref extents) = self.lookup(path)?;
+        Ok(Stat {
+            ftype: ft,
+            ino: ino,
+            len: extents.iter().map(|e| e.length).sum()
+        })
+    }
+
+    fn extent_map(&self, _: Option<RawFd>, path: &Path) -> Result<Vec<FileExtent>, String> {
+        let &(_, _, ref extents) = self.lookup(path).map_err(|e| e.to_string())?;
+        Ok(extents.iter().map(|e| FileExtent { logical: e.logical, physical: e.physical, length: e.length }).collect())
+    }
+}
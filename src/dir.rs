@@ -0,0 +1,371 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Directory enumeration. A libc-based reader instead of `std::fs::read_dir`, since it can open directories
+//! with `O_NOATIME` or relative to a directory fd and keeps listing after an entry that failed to read,
+//! where std's `ReadDir` ends the listing at the first error.
+
+use btrfs::linux::{get_file_extent_map, get_file_extent_map_for_path, FileExtent};
+use libc;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File, FileType, Metadata};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+/// `std::fs::FileType` can't be constructed from a `d_type`, so one sample per type is obtained
+/// via lstat and reused for all further entries of the same type
+pub type FileTypeCache = HashMap<u8, FileType>;
+
+#[derive(Clone)]
+pub struct DirEntry {
+    base: Option<RawFd>,
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+    ftype: Option<FileType>,
+    d_type: u8,
+}
+
+impl DirEntry {
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new(path: PathBuf, ino: u64, ft: FileType) -> DirEntry {
+        DirEntry {
+            base: None,
+            path: path,
+            dev: 0,
+            ino: ino,
+            ftype: Some(ft),
+            d_type: libc::DT_UNKNOWN,
+        }
+    }
+
+    pub fn base(&self) -> Option<RawFd> {
+        self.base
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Device of the directory the entry was read from
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    pub fn file_type(&self, known: &mut FileTypeCache) -> io::Result<FileType> {
+        if let Some(ft) = self.ftype {
+            return Ok(ft);
+        }
+
+        if let Some(ft) = known.get(&self.d_type) {
+            return Ok(*ft);
+        }
+
+        let ft = symlink_metadata_at(self.base, &self.path)?.file_type();
+        // the entry may have been replaced since it was read, only cache consistent samples
+        if d_type_matches(self.d_type, &ft) {
+            known.insert(self.d_type, ft);
+        }
+        Ok(ft)
+    }
+}
+
+static NO_OPENAT2: AtomicBool = AtomicBool::new(false);
+
+/// Lookups relative to a directory fd are confined beneath it, so that directories replaced by symlinks
+/// or moved around during the walk can't lead outside of the tree. Kernels without openat2 fall back to plain openat.
+fn open_at(base: Option<RawFd>, path: &Path, flags: libc::c_int) -> io::Result<RawFd> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    if let (Some(base), false) = (base, NO_OPENAT2.load(AtomicOrdering::Relaxed)) {
+        let mut how: libc::open_how = unsafe { mem::zeroed() };
+        how.flags = (flags | libc::O_CLOEXEC) as u64;
+        how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+        let fd = unsafe { libc::syscall(libc::SYS_openat2, base, cpath.as_ptr(), &how as *const libc::open_how, mem::size_of::<libc::open_how>()) };
+        if fd >= 0 {
+            return Ok(fd as RawFd);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ENOSYS) {
+            return Err(err);
+        }
+        NO_OPENAT2.store(true, AtomicOrdering::Relaxed);
+    }
+    let fd = unsafe { libc::openat(base.unwrap_or(libc::AT_FDCWD), cpath.as_ptr(), flags | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// lstat, optionally relative to a directory fd instead of the working directory
+pub fn symlink_metadata_at(base: Option<RawFd>, path: &Path) -> io::Result<Metadata> {
+    if base.is_none() {
+        return fs::symlink_metadata(path);
+    }
+    // O_PATH does not require read permission and std can fstat it for us
+    let fd = open_at(base, path, libc::O_PATH | libc::O_NOFOLLOW)?;
+    let f = unsafe { File::from_raw_fd(fd) };
+    f.metadata()
+}
+
+/// Opens a file for reading, optionally relative to a directory fd.
+/// Like for directories `O_NOATIME` falls back to a normal open on EPERM.
+pub fn open_file_at(base: Option<RawFd>, path: &Path, noatime: bool) -> io::Result<File> {
+    let fd = match open_at(base, path, if noatime { libc::O_RDONLY | libc::O_NOATIME } else { libc::O_RDONLY }) {
+        Err(ref e) if noatime && e.raw_os_error() == Some(libc::EPERM) => open_at(base, path, libc::O_RDONLY)?,
+        r => r?
+    };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// `(resident, total)` pages of a file's page cache as reported by mincore
+pub fn resident_pages(base: Option<RawFd>, path: &Path, noatime: bool) -> io::Result<(u64, u64)> {
+    let f = open_file_at(base, path, noatime)?;
+    let len = f.metadata()?.len() as usize;
+    if len == 0 {
+        return Ok((0, 0));
+    }
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut vec = vec![0u8; len.div_ceil(page)];
+    unsafe {
+        // mapping alone does not fault anything in
+        let addr = libc::mmap(::std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, f.as_raw_fd(), 0);
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let ret = libc::mincore(addr, len, vec.as_mut_ptr());
+        let err = io::Error::last_os_error();
+        libc::munmap(addr, len);
+        if ret != 0 {
+            return Err(err);
+        }
+    }
+    let resident = vec.iter().filter(|&&p| p & 1 != 0).count();
+    Ok((resident as u64, vec.len() as u64))
+}
+
+/// readlink, optionally relative to a directory fd
+pub fn read_link_at(base: Option<RawFd>, path: &Path) -> io::Result<PathBuf> {
+    if base.is_none() {
+        return fs::read_link(path);
+    }
+    // an empty path refers to the O_PATH fd itself, which keeps the lookup confined like any other
+    let fd = open_at(base, path, libc::O_PATH | libc::O_NOFOLLOW)?;
+    let link = unsafe { File::from_raw_fd(fd) };
+    let mut buf: Vec<u8> = Vec::with_capacity(256);
+    loop {
+        let len = unsafe { libc::readlinkat(link.as_raw_fd(), b"\0".as_ptr() as *const libc::c_char, buf.as_mut_ptr() as *mut libc::c_char, buf.capacity()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // the target may have been truncated, retry with more room
+        if (len as usize) < buf.capacity() {
+            unsafe { buf.set_len(len as usize); }
+            return Ok(PathBuf::from(OsStr::from_bytes(&buf)));
+        }
+        let cap = buf.capacity();
+        buf.reserve(cap * 2);
+    }
+}
+
+/// statx without following symlinks, optionally relative to a directory fd
+pub fn statx_at(base: Option<RawFd>, path: &Path, mask: u32) -> io::Result<libc::statx> {
+    let mut buf: libc::statx = unsafe { mem::zeroed() };
+    let ret = match base {
+        Some(_) => {
+            let fd = open_at(base, path, libc::O_PATH | libc::O_NOFOLLOW)?;
+            let f = unsafe { File::from_raw_fd(fd) };
+            unsafe { libc::statx(f.as_raw_fd(), b"\0".as_ptr() as *const libc::c_char, libc::AT_EMPTY_PATH | libc::AT_STATX_SYNC_AS_STAT, mask, &mut buf) }
+        }
+        None => {
+            let cpath = CString::new(path.as_os_str().as_bytes())?;
+            unsafe { libc::statx(libc::AT_FDCWD, cpath.as_ptr(), libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT, mask, &mut buf) }
+        }
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buf)
+}
+
+/// FIEMAP, optionally relative to a directory fd instead of the working directory
+pub fn extent_map_at(base: Option<RawFd>, path: &Path) -> Result<Vec<FileExtent>, String> {
+    if base.is_none() {
+        return get_file_extent_map_for_path(path);
+    }
+    let fd = open_at(base, path, libc::O_RDONLY).map_err(|e| e.to_string())?;
+    // owned so the fd gets closed
+    let f = unsafe { File::from_raw_fd(fd) };
+    get_file_extent_map(f.as_raw_fd())
+}
+
+fn d_type_matches(d_type: u8, ft: &FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    match d_type {
+        libc::DT_DIR => ft.is_dir(),
+        libc::DT_REG => ft.is_file(),
+        libc::DT_LNK => ft.is_symlink(),
+        libc::DT_FIFO => ft.is_fifo(),
+        libc::DT_SOCK => ft.is_socket(),
+        libc::DT_CHR => ft.is_char_device(),
+        libc::DT_BLK => ft.is_block_device(),
+        _ => false
+    }
+}
+
+pub struct DirIter {
+    dev: u64,
+    inner: Inner,
+}
+
+enum Inner {
+    Raw(RawDir),
+    #[cfg(any(test, feature = "testing"))]
+    Fake(::std::vec::IntoIter<io::Result<DirEntry>>),
+}
+
+impl DirIter {
+    pub fn open(base: Option<RawFd>, path: &Path, noatime: bool) -> io::Result<DirIter> {
+        let dir = RawDir::open(base, path, noatime)?;
+        let dev = dir.dev()?;
+
+        Ok(DirIter {
+            dev: dev,
+            inner: Inner::Raw(dir)
+        })
+    }
+
+    /// Device of the directory being read
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn from_entries(entries: Vec<io::Result<DirEntry>>) -> DirIter {
+        DirIter {
+            dev: 0,
+            inner: Inner::Fake(entries.into_iter())
+        }
+    }
+}
+
+impl Iterator for DirIter {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        let next = match self.inner {
+            Inner::Raw(ref mut iter) => iter.next(),
+            #[cfg(any(test, feature = "testing"))]
+            Inner::Fake(ref mut iter) => iter.next()
+        };
+
+        let dev = self.dev;
+        next.map(|r| r.map(|mut dent| { dent.dev = dev; dent }))
+    }
+}
+
+pub struct RawDir {
+    dir: *mut libc::DIR,
+    base: Option<RawFd>,
+    path: PathBuf,
+    done: bool,
+}
+
+impl RawDir {
+    /// `O_NOATIME` is only permitted for the owner of a directory or a privileged process.
+    /// On EPERM the directory is opened normally instead.
+    fn open(base: Option<RawFd>, path: &Path, noatime: bool) -> io::Result<RawDir> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+
+        let fd = match open_at(base, path, if noatime { flags | libc::O_NOATIME } else { flags }) {
+            Err(ref e) if noatime && e.raw_os_error() == Some(libc::EPERM) => open_at(base, path, flags)?,
+            r => r?
+        };
+
+        let dir = unsafe { libc::fdopendir(fd) };
+        if dir.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+
+        Ok(RawDir {
+            dir: dir,
+            base: base,
+            path: path.to_owned(),
+            done: false
+        })
+    }
+
+    /// Asks the open directory instead of resolving the path again, which might have been replaced by now
+    fn dev(&self) -> io::Result<u64> {
+        let mut st: libc::stat64 = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat64(libc::dirfd(self.dir), &mut st) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(st.st_dev as u64)
+    }
+}
+
+impl Iterator for RawDir {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        while !self.done {
+            // readdir only signals errors through errno
+            unsafe { *libc::__errno_location() = 0; }
+            let pos = unsafe { libc::telldir(self.dir) };
+            let ent = unsafe { libc::readdir64(self.dir) };
+
+            if ent.is_null() {
+                let err = io::Error::last_os_error();
+                // a bad entry that the position moved past doesn't end the listing, a stuck one would fail forever
+                self.done = err.raw_os_error() == Some(0) || unsafe { libc::telldir(self.dir) } == pos;
+                if err.raw_os_error() == Some(0) {
+                    return None;
+                }
+                return Some(Err(err));
+            }
+
+            let ent = unsafe { &*ent };
+            let name = unsafe { CStr::from_ptr(ent.d_name.as_ptr()) }.to_bytes();
+            if name == b"." || name == b".." {
+                continue;
+            }
+
+            return Some(Ok(DirEntry {
+                base: self.base,
+                path: self.path.join(OsStr::from_bytes(name)),
+                dev: 0,
+                ino: ent.d_ino,
+                ftype: None,
+                d_type: ent.d_type,
+            }));
+        }
+
+        None
+    }
+}
+
+impl Drop for RawDir {
+    fn drop(&mut self) {
+        unsafe { libc::closedir(self.dir); }
+    }
+}
@@ -8,30 +8,246 @@ extern crate btrfs2 as btrfs;
 extern crate mnt;
 extern crate libc;
 
-use btrfs::linux::{get_file_extent_map_for_path, FileExtent};
+mod backend;
+mod dir;
+mod multi;
+mod paired;
+mod reader;
+mod spill;
+#[cfg(test)]
+mod tests;
+mod trace;
+
+use btrfs::linux::FileExtent;
 use std::fs::*;
-use std::os::unix::fs::DirEntryExt;
 use std::path::PathBuf;
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::ops::Bound::{Included, Excluded};
 use std::path::Path;
-use std::os::unix::fs::MetadataExt;
-use std::os::unix::io::AsRawFd;
-
-pub struct Entry {
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::fmt;
+use std::cmp::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::ops::BitOr;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
+use std::sync::mpsc::SyncSender;
+use std::error::Error;
+use std::borrow::Cow;
+use backend::{Backend, RealFs};
+use dir::{DirIter, FileTypeCache};
+use spill::Spill;
+pub use multi::{DeviceId, MultiScan};
+pub use paired::{DiffEvent, PairedScan};
+pub use reader::ContentReader;
+pub use trace::{Replay, TraceEvent, WalkTrace};
+pub use mnt::MountEntry;
+#[cfg(any(test, feature = "testing"))]
+pub use backend::FakeFs;
+
+pub struct Entry<T = ()> {
+    /// Full path instead of a shared `(Arc<Path>, OsString)` parent and name. The name would still be one
+    /// allocation per entry, so the count stays the same while every `path()` would have to join them first.
+    /// What sharing saves is bytes: on /usr/share paths average 53 bytes and names 27, about 10 bytes per entry
+    /// once the 16 byte `Arc<Path>` is paid for, i.e. some 10KiB per default batch of 1024 entries.
     path: PathBuf,
     ftype: FileType,
+    depth: usize,
+    dev: u64,
     ino: u64,
     extents: Vec<FileExtent>,
+    order: Order,
+    /// physical offset the entry was scheduled at, if it was yielded in physical order
+    offset: u64,
+    statx: Option<Box<libc::statx>>,
+    /// directory of an fd root the path is relative to, kept open for as long as entries below it exist
+    base: Option<Arc<File>>,
+    tag: T,
+    cached: Option<bool>,
+    descend_failed: bool,
+    /// where the content pass placed the file when a device map is set, devices are swept in rank order
+    primary_device: Option<Arc<PathBuf>>,
+    device_rank: u32,
+    /// swept before higher ones in the content pass, see `ToScan::set_size_priority`
+    size_bucket: u8,
+    /// extent bytes of files whose extents were only looked up to place them, not kept
+    located_bytes: u64,
 }
 
 impl Entry {
     pub fn new(buf: PathBuf, ft: FileType, ino: u64, extents: Vec<FileExtent>) -> Entry {
+        Entry::with_tag(buf, ft, ino, extents, ())
+    }
+}
+
+impl<T> Entry<T> {
+    /// For walkers created with `ToScan::with_tags`, entries discovered below this one inherit the tag
+    pub fn with_tag(buf: PathBuf, ft: FileType, ino: u64, extents: Vec<FileExtent>, tag: T) -> Entry<T> {
         Entry {
             path: buf,
             ftype: ft,
+            depth: 0,
+            dev: 0,
             ino :ino,
-            extents: extents
+            extents: extents,
+            order: Dentries,
+            offset: 0,
+            statx: None,
+            base: None,
+            tag: tag,
+            cached: None,
+            descend_failed: false,
+            primary_device: None,
+            device_rank: 0,
+            size_bucket: 0,
+            located_bytes: 0
+        }
+    }
+
+    /// Takes over the path of the directory entry, which spares an allocation for every yielded entry
+    fn from_dent(dent: dir::DirEntry, depth: usize, ft: FileType, extents: Vec<FileExtent>, tag: T, base: Option<Arc<File>>) -> Entry<T> {
+        let (ino, dev) = (dent.ino(), dent.dev());
+        let mut e = Entry::with_tag(dent.into_path(), ft, ino, extents, tag);
+        e.depth = depth;
+        e.dev = dev;
+        e.base = base;
+        e
+    }
+
+    fn base_fd(&self) -> Option<RawFd> {
+        self.base.as_ref().map(|f| f.as_raw_fd())
+    }
+
+    fn with_order(mut self, order: Order) -> Entry<T> {
+        self.order = order;
+        self
+    }
+
+    fn at_offset(mut self, offset: u64) -> Entry<T> {
+        self.offset = offset;
+        self
+    }
+
+    fn fetch_statx(mut self, mask: u32) -> Entry<T> {
+        if mask != 0 {
+            self.statx = dir::statx_at(self.base_fd(), &self.path, mask).ok().map(Box::new);
+        }
+        self
+    }
+
+    /// Metadata fetched during the inode pass if a mask was set via `ToScan::set_statx_mask`.
+    /// Only the fields flagged in `stx_mask` are valid, which may be fewer than requested.
+    /// None if statx is not supported by the kernel or the call failed.
+    pub fn statx(&self) -> Option<&libc::statx> {
+        self.statx.as_deref()
+    }
+
+    fn statx_field(&self, flag: u32) -> Option<&libc::statx> {
+        self.statx().filter(|stx| stx.stx_mask & flag == flag)
+    }
+
+    /// Fetched if `MetaFields::SIZE` was requested via `ToScan::set_required_metadata`
+    pub fn size(&self) -> Option<u64> {
+        self.statx_field(libc::STATX_SIZE).map(|stx| stx.stx_size)
+    }
+
+    /// Fetched if `MetaFields::MTIME` was requested via `ToScan::set_required_metadata`
+    pub fn mtime(&self) -> Option<SystemTime> {
+        self.statx_field(libc::STATX_MTIME).map(|stx| statx_time(stx.stx_mtime))
+    }
+
+    /// Whether all of the file's pages were already in the page cache when it was yielded, i.e. reading it
+    /// won't stall on the disk. Only determined for content ordered files with `ToScan::set_measure_cache_residency`
+    /// enabled and None for everything else, including empty files.
+    pub fn was_cached(&self) -> Option<bool> {
+        self.cached
+    }
+
+    /// The device holding the start of the file's data as translated by `ToScan::set_device_map`.
+    /// Only known for files that went through the content pass while a device map was set.
+    pub fn primary_device(&self) -> Option<&Path> {
+        self.primary_device.as_ref().map(|d| d.as_path())
+    }
+
+    /// Whether this is a placeholder for a directory that could not be read, see `ToScan::set_yield_denied_dirs`
+    pub fn descend_failed(&self) -> bool {
+        self.descend_failed
+    }
+
+    /// Only true if the birth time is known, see `ToScan::set_created_after`
+    fn created_before(&self, cutoff: Option<SystemTime>) -> bool {
+        match (cutoff, self.btime()) {
+            (Some(cutoff), Some(btime)) => btime < cutoff,
+            _ => false
+        }
+    }
+
+    /// Creation time. Fetched if `MetaFields::BTIME` was requested via `ToScan::set_required_metadata`
+    /// and the filesystem records it.
+    pub fn btime(&self) -> Option<SystemTime> {
+        self.statx_field(libc::STATX_BTIME).map(|stx| statx_time(stx.stx_btime))
+    }
+
+    /// Permissions and file type bits. Fetched if `MetaFields::MODE` was requested via `ToScan::set_required_metadata`
+    pub fn mode(&self) -> Option<u32> {
+        self.statx_field(libc::STATX_TYPE | libc::STATX_MODE).map(|stx| stx.stx_mode as u32)
+    }
+
+    /// Fetched if `MetaFields::NLINK` was requested via `ToScan::set_required_metadata`
+    pub fn nlink(&self) -> Option<u64> {
+        self.statx_field(libc::STATX_NLINK).map(|stx| stx.stx_nlink as u64)
+    }
+
+    /// Device the entry itself resides on, which differs from `dev` for mount points.
+    /// Fetched if `MetaFields::DEV` was requested via `ToScan::set_required_metadata`
+    pub fn device(&self) -> Option<u64> {
+        self.statx().map(|stx| libc::makedev(stx.stx_dev_major, stx.stx_dev_minor))
+    }
+
+    /// The ordering that actually determined when this entry was yielded.
+    /// This can be weaker than the requested `Order`, e.g. in content order
+    /// entries for which no extents could be found are only ordered by inode
+    /// and files without extents in single sweep mode are yielded in discovery order.
+    pub fn effective_order(&self) -> Order {
+        self.order
+    }
+
+    /// Compares by the key `Order::Content` yields files in, e.g. to re-sort entries collected in another order
+    /// without walking again. That's the offset the entry was scheduled at if it was yielded in content or
+    /// rotational order, otherwise its lowest physical extent. Entries for which neither is known, including
+    /// everything yielded in inode or dentry order without extents, are placed by `unknown` with `Drop` sorting last.
+    /// Ties go to the lower inode like in the content pass. Entries are grouped by size bucket and device first,
+    /// if the walk that produced them had a size priority or device map set.
+    pub fn physical_cmp(&self, other: &Entry<T>, unknown: UnknownOffsetPolicy) -> Ordering {
+        let key = |e: &Entry<T>| {
+            let offset = match e.order {
+                Content | RotationalOptimal => Some(e.offset),
+                _ => lowest_physical(&e.extents)
+            };
+            match offset {
+                Some(offset) => (e.sweep(), offset, e.ino),
+                None => {
+                    let (offset, bucket, rank) = unknown.placement().unwrap_or((std::u64::MAX, std::u8::MAX, std::u32::MAX));
+                    ((bucket, rank), offset, e.ino)
+                }
+            }
+        };
+        key(self).cmp(&key(other))
+    }
+
+    /// Leaves of a content pass batch are swept separately per size bucket, then per device
+    fn sweep(&self) -> (u8, u32) {
+        (self.size_bucket, self.device_rank)
+    }
+
+    /// The key the entry was ordered by, `position` being its index in the output
+    fn schedule_key(&self, position: u64) -> u64 {
+        match self.order {
+            Content | Readiness | RotationalOptimal => self.offset,
+            Inode => self.ino,
+            Dentries | DirsThenFiles | ReaddirNatural => position
         }
     }
 
@@ -39,48 +255,367 @@ impl Entry {
         self.ino
     }
 
+    /// The tag of the root or injected entry this one was discovered below
+    pub fn tag(&self) -> &T {
+        &self.tag
+    }
+
+    /// Device id of the directory the entry was found in, which together with `ino` identifies it.
+    /// For mount points this is the device of the parent, matching the inode number from the parent's listing.
+    /// 0 for entries not discovered by the walk, e.g. roots.
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    /// Type of the entry itself as reported by lstat, symlinks are never followed
     pub fn file_type(&self) -> FileType {
         self.ftype
     }
 
+    /// Where a symlink points to, None for all other file types.
+    /// Not cached, every call is a syscall.
+    pub fn symlink_target(&self) -> Option<std::io::Result<PathBuf>> {
+        if !self.ftype.is_symlink() {
+            return None;
+        }
+        Some(dir::read_link_at(self.base_fd(), &self.path))
+    }
+
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
 
+    /// The last path component, or the whole path if there is none, e.g. for `.` roots
+    pub fn file_name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or(self.path.as_os_str())
+    }
+
+    /// Number of directories between the entry and its root, 0 for the root itself.
+    /// Determined at discovery and kept while entries are buffered for the inode and content passes,
+    /// so together with the path it is enough to rebuild the hierarchy from a physically ordered stream
+    /// in which children may come before their parents.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Metadata of the entry itself without following symlinks. Not cached, every call is a syscall.
+    pub fn metadata(&self) -> std::io::Result<Metadata> {
+        dir::symlink_metadata_at(self.base_fd(), &self.path)
+    }
+
+    /// Logical file size as reported by lstat, i.e. the number of bytes a reader will get.
+    /// Not cached, every call is a syscall.
+    pub fn len(&self) -> std::io::Result<u64> {
+        self.metadata().map(|m| m.len())
+    }
+
+    /// Whether `len` is 0, equally a syscall
+    pub fn is_empty(&self) -> std::io::Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Extents as reported by FIEMAP, empty if they weren't looked up or there are none
+    pub fn extents(&self) -> &[FileExtent] {
+        &self.extents
+    }
+
+    /// Bytes of physical IO needed to read the extents, used for the prefetch budget.
+    ///
+    /// FIEMAP only reports logical extent lengths and the btrfs crate does not expose the encoded
+    /// (compressed) on-disk length, so for compressed extents this overestimates the IO.
+    /// That errs on the side of a smaller prefetch window, not of overcommitting the page cache.
+    /// Holes and inline data have no extents and don't count.
     fn extent_sum(&self) -> u64 {
         self.extents.iter().map(|e| e.length).sum()
     }
+
+    /// Extent bytes whether the extents were kept or not, counted against `ToScan::set_max_bytes`
+    fn data_bytes(&self) -> u64 {
+        self.extent_sum() + self.located_bytes
+    }
+}
+
+/// An IO error together with the path on which it occurred.
+/// Errors don't end the walk, the next call carries on with the siblings of the failed entry. For errors
+/// reading a directory itself that's the directory's path, its remaining entries are still listed if possible.
+#[derive(Debug)]
+pub struct WalkError {
+    path: PathBuf,
+    source: std::io::Error,
 }
 
-impl PartialEq for Entry {
-    fn eq(&self, other: &Entry) -> bool {
+impl WalkError {
+    fn new(path: PathBuf, source: std::io::Error) -> WalkError {
+        WalkError {
+            path: path,
+            source: source
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn io_error(&self) -> &std::io::Error {
+        &self.source
+    }
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.source)
+    }
+}
+
+impl Error for WalkError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<WalkError> for std::io::Error {
+    fn from(e: WalkError) -> std::io::Error {
+        std::io::Error::new(e.source.kind(), e)
+    }
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Entry<T>) -> bool {
         return self.path.eq(&other.path)
     }
 }
 
-impl PartialEq<Path> for Entry {
+impl<T> PartialEq<Path> for Entry<T> {
     fn eq(&self, p: &Path) -> bool {
         return self.path.eq(p)
     }
 }
 
-pub struct ToScan {
-    phy_sorted : BTreeMap<u64, Entry>,
-    phy_sorted_leaves: Vec<(u64, Entry)>,
-    unordered : VecDeque<Entry>,
+pub struct ToScan<T = ()> {
+    backend: Box<Backend>,
+    roots: Vec<PathBuf>,
+    confine_roots: bool,
+    device_size: Option<u64>,
+    phy_sorted : BTreeMap<u64, Entry<T>>,
+    phy_sorted_leaves: BinaryHeap<Leaf<T>>,
+    deferred_leaves: Vec<Leaf<T>>,
+    content_pos: u64,
+    /// size bucket and device rank of the sweep `content_pos` belongs to
+    content_sweep: (u8, u32),
+    /// devices returned by the device map in the order they were first seen
+    device_ranks: HashMap<PathBuf, (u32, Arc<PathBuf>)>,
+    max_seek_gap: Option<u64>,
+    combined_key: Option<(f64, f64)>,
+    pipeline_distance: Option<u64>,
+    unknown_offset_policy: UnknownOffsetPolicy,
+    size_priority: SizePriority,
+    dir_sort_key: DirKey,
+    geometry: Option<Geometry>,
+    cpu_affinity: Option<Vec<usize>>,
+    analysis: ExtentAnalysis,
+    retry: Option<(u32, Duration)>,
+    inode_direction: Direction,
+    unordered : VecDeque<Entry<T>>,
+    /// entries without extents keyed by `(dev, ino)`, used instead of `unordered` in inode and content order
+    inode_sorted: BTreeMap<(u64, u64), Entry<T>>,
     cursor: u64,
-    current_dir: Option<ReadDir>,
-    inode_ordered: Vec<Entry>,
+    cursor_policy: CursorPolicy,
+    current_dir: Option<(PathBuf, DirIter, T, Option<Arc<File>>)>,
+    current_depth: usize,
+    noatime: bool,
+    max_children: usize,
+    /// devices of all directories read so far
+    devices_seen: HashSet<u64>,
+    /// entries read from `current_dir` so far
+    children_read: usize,
+    file_types: FileTypeCache,
+    inode_ordered: Vec<Entry<T>>,
     prefilter: Option<Box<Fn(&Path, &FileType) -> bool>>,
+    descend_filter: Option<Box<Fn(&Path, &FileType) -> bool>>,
+    mount_filter: Option<Box<Fn(&mnt::MountEntry) -> bool>>,
+    mount_table: HashMap<PathBuf, mnt::MountEntry>,
+    entry_filter: Option<Box<Fn(&Path, &FileType) -> FilterDecision>>,
+    case_insensitive: bool,
+    seek_callback: Option<Box<FnMut(u64, u64)>>,
     phase: Phase,
+    phase_started: Option<Instant>,
     order: Order,
     batch_size: usize,
-    prefetched: HashMap<PathBuf, u64>,
+    spill: Option<Spill<T>>,
+    spill_threshold: usize,
+    /// a run that failed to load, reported with the next entry
+    spill_error: Option<WalkError>,
+    content_trigger: Trigger,
+    first_batch_started: bool,
+    skip_inodes: HashSet<(u64, u64)>,
+    statx_mask: u32,
+    created_after: Option<SystemTime>,
+    sort_strategy: SortStrategy,
+    single_sweep: bool,
+    streaming: bool,
+    skip_replaced_dirs: bool,
+    skip_marker: Option<OsString>,
+    yield_denied_dirs: bool,
+    max_path_depth: Option<usize>,
+    yield_root: bool,
+    revalidate: bool,
+    measure_residency: bool,
+    verify_ordering: bool,
+    last_key: Option<u64>,
+    prefetched: HashMap<PathBuf, Prefetched>,
     mountpoints: Vec<mnt::MountEntry>,
-    prefetch_cap: usize
+    prefetch_devices: HashSet<String>,
+    prefetch_cap: usize,
+    prefetch_cap_bounds: (usize, usize),
+    prefetch_cap_policy: CapPolicy,
+    prefetcher: Option<Box<FnMut(&[&Entry<T>])>>,
+    prefetch_filter: Option<Box<Fn(&Entry<T>) -> bool>>,
+    min_fadvise_bytes: u64,
+    max_fadvise_chunk: u64,
+    last_prefetch_ranges: Vec<(PathBuf, u64, u64)>,
+    /// readahead window of each device prefetched from, queried once
+    readahead_sizes: HashMap<PathBuf, Option<u64>>,
+    prefetch_expiry: Option<u64>,
+    prefetch_dir_blocks: bool,
+    device_map: Option<Box<Fn(u64) -> (PathBuf, u64)>>,
+    max_cache_pressure: Option<u64>,
+    max_bytes: Option<u64>,
+    yielded_bytes: u64,
+    /// device bytes advised and not yet dropped again, only tracked with `max_cache_pressure`
+    resident_bytes: u64,
+    /// the prefetched entry that was taken off the queue last, dropped once the next one is taken
+    reading: Option<Prefetched>,
+    stats: Stats,
+    progress: Option<(Duration, Box<FnMut(&Stats)>)>,
+    last_progress: Option<Instant>,
+    warn_missing_extents: bool,
+    trace: Option<WalkTrace>,
+    visited: Option<Vec<(u64, u64, PathBuf)>>,
+}
+
+/// Outcome of the filter set with `ToScan::set_entry_filter`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct FilterDecision {
+    /// Whether the entry itself is returned
+    pub yield_entry: bool,
+    /// Whether to descend into the entry, ignored for non-directories
+    pub descend: bool,
+}
+
+/// Outcome of `ToScan::would_include`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Decision {
+    /// Why the entry wouldn't be yielded, None if it would
+    pub not_yielded: Option<SkipReason>,
+    /// Why the entry wouldn't be descended into, None if it would
+    pub not_descended: Option<SkipReason>,
+}
+
+/// Why an entry is left out or not descended into, see `ToScan::would_include`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SkipReason {
+    /// Rejected by `ToScan::set_entry_filter`
+    EntryFilter,
+    /// Rejected by `ToScan::set_descend_filter`
+    DescendFilter,
+    /// A mount point rejected by `ToScan::set_mount_filter`
+    MountFilter,
+    /// Rejected by `ToScan::set_prefilter`
+    Prefilter,
+    /// Only directories are descended into
+    NotADirectory,
+    /// Directory roots are only yielded with `ToScan::set_yield_root`
+    Root,
+    /// Deeper than `ToScan::set_max_path_depth` allows
+    Depth,
+    /// Contains the marker of `ToScan::set_skip_marker`, or one of the directories above it does
+    SkipMarker,
+    /// One of the directories above it isn't descended into
+    Ancestor,
+    /// Created before the cutoff of `ToScan::set_created_after`
+    CreatedBefore,
+}
+
+/// Counters collected while walking, mostly useful for tuning
+#[derive(Default, Clone, Debug)]
+pub struct Stats {
+    /// Prefetch rounds skipped because the window was still more than half full.
+    /// A high count means entries are consumed slower than the disk can prefetch them.
+    pub prefetch_window_full: u64,
+    /// Prefetch rounds that ran out of queued entries before the window was filled.
+    /// A high count means discovery can't keep up with consumption.
+    pub prefetch_window_starved: u64,
+    /// Queued directories that were no longer directories when the walk got to them
+    /// and were skipped because of `ToScan::set_skip_replaced_dirs`.
+    pub replaced_dirs: u64,
+    /// Directories skipped with their subtree because they contained the `ToScan::set_skip_marker` file
+    pub marked_dirs: u64,
+    /// Files whose extents were looked up to order them by content
+    pub files_mapped: u64,
+    /// Non-empty regular files among `files_mapped` for which no extents were found.
+    /// If this makes up a large share FIEMAP is probably not supported and content order is ineffective.
+    pub files_without_extents: u64,
+    /// Bytes advised by prefetching, including the gaps merged by `ToScan::set_min_fadvise_bytes`
+    pub prefetched_bytes: u64,
+    /// Extent bytes of prefetched entries that were later taken off the queue.
+    /// Falls short of `prefetched_bytes` by what was evicted, expired, skipped or never reached.
+    pub consumed_bytes: u64,
+    /// Wall-clock time spent in each phase, sampled when the walk switches between them.
+    /// This includes the time the consumer takes between two entries of the phase.
+    pub dir_walk_time: Duration,
+    pub inode_pass_time: Duration,
+    pub content_pass_time: Duration,
+    /// Time spent issuing advises, which can block once the device's request queue is full
+    pub prefetch_time: Duration,
+    /// Files written to disk by `ToScan::set_spill_dir` to be merged back during the content pass
+    pub spilled_entries: u64,
+    /// Pages of content ordered files checked for residency and how many of them were already cached
+    /// when the file was yielded, see `ToScan::set_measure_cache_residency`
+    pub pages_checked: u64,
+    pub pages_resident: u64,
+}
+
+impl Stats {
+    /// Share of the checked pages that were already cached, None if nothing was checked
+    pub fn cache_hit_fraction(&self) -> Option<f64> {
+        if self.pages_checked == 0 {
+            return None;
+        }
+        Some(self.pages_resident as f64 / self.pages_checked as f64)
+    }
 }
 
-#[derive(PartialEq, Copy, Clone)]
+/// A file scheduled for the content pass at its physical offset, on its primary device if there is a device map.
+/// Ordered in reverse so that `BinaryHeap` pops the lowest sweep and offset first, ties go to the lower inode.
+struct Leaf<T>(u64, Entry<T>);
+
+impl<T> Leaf<T> {
+    fn key(&self) -> ((u8, u32), u64, u64) {
+        (self.1.sweep(), self.0, self.1.ino)
+    }
+}
+
+impl<T> PartialEq for Leaf<T> {
+    fn eq(&self, other: &Leaf<T>) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T> Eq for Leaf<T> {}
+
+impl<T> PartialOrd for Leaf<T> {
+    fn partial_cmp(&self, other: &Leaf<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Leaf<T> {
+    fn cmp(&self, other: &Leaf<T>) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Order {
     /// Return directory entries as they are encountered
     /// Only directories are visited sequentially based on physical layout
@@ -92,7 +627,244 @@ pub enum Order {
     Inode,
     /// Return directory entries sorted by physical offset of the file contents
     /// Can be used to get sequential reads over multiple files
-    Content
+    Content,
+    /// Like `Content` in single sweep mode, but among the queued entries whose extents have already been advised
+    /// the one advised longest ago is yielded first, approximating the entry most likely to be in the page cache.
+    /// Minimizes stalls of the consumer rather than seeks. Without `prefetch_dirs` this degrades to single sweep content order.
+    Readiness,
+    /// Return all directories as they are encountered and only once the whole tree has been discovered
+    /// return the files, sorted like `Content`. The batch size is ignored since all files have to be held back,
+    /// which can take a lot of memory on large trees.
+    DirsThenFiles,
+    /// Like `Content`, but within a batch the next file is the one with the shortest estimated access time
+    /// from the previous one, taking rotational latency into account and not just seek distance.
+    /// Only makes sense for a single dedicated spindle whose geometry is set via `ToScan::set_geometry`,
+    /// without it this is the same as `Content`.
+    RotationalOptimal,
+    /// Return directory entries exactly in the order `read_dir` produces them, visiting directories breadth-first
+    /// in the order they were encountered. Unlike `Dentries` directories are not reordered by their physical location
+    /// and nothing is prefetched, which is only useful to reproduce the traversal of other tools.
+    ReaddirNatural
+}
+
+/// See `ToScan::set_sort_strategy`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SortStrategy {
+    /// Entries with equal keys keep their discovery order, at the cost of a temporary buffer half the size of the batch
+    Stable,
+    /// Sorts in place, entries with equal keys end up in no particular order
+    Unstable
+}
+
+impl SortStrategy {
+    fn sort<T, K: Ord, F: FnMut(&Entry<T>) -> K>(&self, entries: &mut [Entry<T>], key: F) {
+        match *self {
+            SortStrategy::Stable => entries.sort_by_key(key),
+            SortStrategy::Unstable => entries.sort_unstable_by_key(key)
+        }
+    }
+}
+
+/// How the number of entries prefetched ahead adapts to hits and misses, see `ToScan::set_prefetch_cap_policy`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum CapPolicy {
+    /// Double on every hit, drop to the minimum on a miss
+    Reset,
+    /// Double on every hit, halve on a miss
+    Halve,
+    /// Grow by the step on every hit, shrink by it on a miss
+    Linear(usize)
+}
+
+impl CapPolicy {
+    fn next(&self, cap: usize, hit: bool) -> usize {
+        match (*self, hit) {
+            (CapPolicy::Reset, true) | (CapPolicy::Halve, true) => cap * 2 + 1,
+            (CapPolicy::Reset, false) => 0,
+            (CapPolicy::Halve, false) => cap / 2,
+            (CapPolicy::Linear(step), true) => cap.saturating_add(step),
+            (CapPolicy::Linear(step), false) => cap.saturating_sub(step)
+        }
+    }
+}
+
+/// What to do with queued directories behind the cursor once it reaches the end of the disk
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum CursorPolicy {
+    /// Seek back to the lowest queued offset and sweep forward again, as often as needed
+    Wrap,
+    /// Never seek backwards. Whatever ended up behind the cursor is processed as an unordered tail
+    SingleSweep
+}
+
+/// Which files the content pass takes first, see `ToScan::set_size_priority`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SizePriority {
+    SmallFirst,
+    LargeFirst,
+    /// Sweep all files of a batch together, the default
+    None
+}
+
+impl SizePriority {
+    /// Buckets grow by a factor of 16: below 16 bytes, below 256 bytes, ..., below 4KiB and so on.
+    /// The first and last bucket are left to files without extents, see `UnknownOffsetPolicy::placement`
+    fn bucket(self, size: u64) -> u8 {
+        let magnitude = ((64 - size.leading_zeros()) / 4) as u8;
+        match self {
+            SizePriority::SmallFirst => 1 + magnitude,
+            SizePriority::LargeFirst => std::u8::MAX - 1 - magnitude,
+            SizePriority::None => 1
+        }
+    }
+}
+
+/// On-disk representation for `ToScan::write_manifest`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ManifestFormat {
+    /// `offset\tino\tpath` lines. Paths are written as raw bytes with backslash, tab and newline escaped as `\\`, `\t` and `\n`
+    Tsv,
+    /// An array of `{"offset": .., "ino": .., "path": ..}` objects, one per line. Fails on paths that aren't valid UTF-8
+    Json
+}
+
+/// Placement of files without extents in the content pass, e.g. empty or inline files or when FIEMAP is not supported
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum UnknownOffsetPolicy {
+    /// Treat them as being at offset 0, i.e. yield them at the start of each batch
+    First,
+    /// Yield them at the end of each batch
+    Last,
+    /// Don't yield them at all
+    Drop
+}
+
+impl UnknownOffsetPolicy {
+    /// Offset, size bucket and device rank for the content pass, i.e. before or after every sweep. None to drop the file
+    fn placement(self) -> Option<(u64, u8, u32)> {
+        match self {
+            UnknownOffsetPolicy::First => Some((0, 0, 0)),
+            UnknownOffsetPolicy::Last => Some((std::u64::MAX, std::u8::MAX, std::u32::MAX)),
+            UnknownOffsetPolicy::Drop => None
+        }
+    }
+}
+
+fn statx_time(t: libc::statx_timestamp) -> SystemTime {
+    let secs = Duration::from_secs(t.tv_sec.unsigned_abs());
+    let base = if t.tv_sec >= 0 { UNIX_EPOCH + secs } else { UNIX_EPOCH - secs };
+    base + Duration::new(0, t.tv_nsec)
+}
+
+/// Set of metadata fields for `ToScan::set_required_metadata`, combined with `|`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct MetaFields(u32);
+
+impl MetaFields {
+    pub const INO: MetaFields = MetaFields(1 << 0);
+    pub const SIZE: MetaFields = MetaFields(1 << 1);
+    pub const MTIME: MetaFields = MetaFields(1 << 2);
+    pub const MODE: MetaFields = MetaFields(1 << 3);
+    pub const NLINK: MetaFields = MetaFields(1 << 4);
+    pub const DEV: MetaFields = MetaFields(1 << 5);
+    pub const BTIME: MetaFields = MetaFields(1 << 6);
+
+    pub fn empty() -> MetaFields {
+        MetaFields(0)
+    }
+
+    pub fn all() -> MetaFields {
+        MetaFields::INO | MetaFields::SIZE | MetaFields::MTIME | MetaFields::MODE | MetaFields::NLINK | MetaFields::DEV | MetaFields::BTIME
+    }
+
+    pub fn contains(&self, other: MetaFields) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn statx_mask(&self) -> u32 {
+        let mut mask = 0;
+        if self.contains(MetaFields::INO) { mask |= libc::STATX_INO; }
+        if self.contains(MetaFields::SIZE) { mask |= libc::STATX_SIZE; }
+        if self.contains(MetaFields::MTIME) { mask |= libc::STATX_MTIME; }
+        if self.contains(MetaFields::MODE) { mask |= libc::STATX_TYPE | libc::STATX_MODE; }
+        if self.contains(MetaFields::NLINK) { mask |= libc::STATX_NLINK; }
+        // the device is always filled in, but some field has to be requested to make the call at all
+        if self.contains(MetaFields::DEV) { mask |= libc::STATX_TYPE; }
+        if self.contains(MetaFields::BTIME) { mask |= libc::STATX_BTIME; }
+        mask
+    }
+}
+
+impl BitOr for MetaFields {
+    type Output = MetaFields;
+
+    fn bitor(self, rhs: MetaFields) -> MetaFields {
+        MetaFields(self.0 | rhs.0)
+    }
+}
+
+/// When the first inode and content pass starts, see `ToScan::set_content_trigger`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Trigger {
+    /// Once a full batch has been collected
+    BatchSize,
+    /// As soon as the first directory has been read completely
+    FirstDirComplete,
+    /// Once this many entries have been collected
+    AfterNFiles(usize)
+}
+
+/// See `ToScan::set_inode_direction`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Direction {
+    Ascending,
+    Descending
+}
+
+/// What directories are ordered by during the directory walk, see `ToScan::set_dir_sort_key`
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum DirKey {
+    /// Lowest physical offset of the directory's data blocks, i.e. where its entries are stored
+    DataExtent,
+    /// Inode number, which follows the inode table layout on filesystems that have one
+    Inode
+}
+
+/// Disk geometry for `Order::RotationalOptimal`. Zoned recording means the number of sectors per track
+/// varies across the platter, an average is good enough to estimate rotational positions locally.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Geometry {
+    pub sector_size: u64,
+    pub sectors_per_track: u64,
+    pub rpm: u32,
+    /// Time to move the head by a single track, longer seeks are assumed to grow with the square root of the distance
+    pub track_seek_us: u32,
+}
+
+impl Geometry {
+    /// Estimated microseconds from the head being at the start of `from` until the start of `to` passes under it
+    fn access_time(&self, from: u64, to: u64) -> u64 {
+        let spt = std::cmp::max(1, self.sectors_per_track);
+        let (from, to) = (from / std::cmp::max(1, self.sector_size), to / std::cmp::max(1, self.sector_size));
+        let tracks = (from / spt).abs_diff(to / spt);
+        let seek_us = self.track_seek_us as f64 * (tracks as f64).sqrt();
+        let rotation_us = 60_000_000.0 / std::cmp::max(1, self.rpm) as f64;
+        // fractions of a rotation, the platter keeps spinning while the head seeks
+        let arrival = (from % spt) as f64 / spt as f64 + seek_us / rotation_us;
+        let target = (to % spt) as f64 / spt as f64;
+        let wait = ((target - arrival) % 1.0 + 1.0) % 1.0;
+        (seek_us + wait * rotation_us) as u64
+    }
+}
+
+/// Bookkeeping for an entry whose extents have been advised
+struct Prefetched {
+    size: u64,
+    advised: Instant,
+    /// schedule key in `phy_sorted`, None for unordered entries
+    pos: Option<u64>,
+    /// `(device, offset, length)` runs that were advised, kept so `ToScan::finish` can undo them
+    ranges: Vec<(PathBuf, u64, u64)>,
 }
 
 #[derive(PartialEq)]
@@ -108,20 +880,128 @@ use Order::*;
 impl ToScan {
 
     pub fn new() -> ToScan {
+        ToScan::with_tags()
+    }
+
+    /// A walker over a synthetic tree instead of the real filesystem, for testing the scheduling logic.
+    /// Only the directory walk and extent lookups are served by it, prefetching and the
+    /// `Entry` accessors that perform syscalls still hit the real filesystem.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_fake_fs(fs: FakeFs) -> ToScan {
+        let mut scan = ToScan::new();
+        scan.backend = Box::new(fs);
+        scan
+    }
+
+    /// For trees too large to buffer a batch in memory. Once `set_spill_threshold` files are waiting for the inode pass
+    /// their extents are looked up right away and they're written to a sorted run in `dir`. The content pass merges
+    /// all runs of a batch with what's still in memory, so a batch size covering the whole tree yields one global
+    /// physical order at the price of writing and reading every file's record once.
+    /// The files are unlinked as soon as they're created.
+    ///
+    /// Applies to `Order::Content`, `Order::RotationalOptimal` and `Order::DirsThenFiles` unless a pipeline distance,
+    /// combined key or device map is set. Only offered for untagged walks since tags can't be written out.
+    /// Failing to write a run is reported as an error and stops spilling, the affected files stay in memory.
+    pub fn set_spill_dir(&mut self, dir: PathBuf) {
+        self.spill = Some(Spill::new(dir, ()));
+    }
+}
+
+impl<T: Clone> ToScan<T> {
+
+    /// A walker whose entries carry a tag of type `T`, see `add_root_tagged` and `Entry::tag`
+    pub fn with_tags() -> ToScan<T> {
         ToScan {
+            backend: Box::new(RealFs),
+            roots: vec![],
+            confine_roots: false,
+            device_size: None,
             phy_sorted: BTreeMap::new(),
-            phy_sorted_leaves: vec![],
+            phy_sorted_leaves: BinaryHeap::new(),
+            deferred_leaves: vec![],
+            content_pos: 0,
+            content_sweep: (0, 0),
+            device_ranks: HashMap::new(),
+            max_seek_gap: None,
+            combined_key: None,
+            pipeline_distance: None,
+            unknown_offset_policy: UnknownOffsetPolicy::First,
+            size_priority: SizePriority::None,
+            dir_sort_key: DirKey::DataExtent,
+            geometry: None,
+            cpu_affinity: None,
+            analysis: Default::default(),
+            retry: None,
+            inode_direction: Direction::Ascending,
             unordered: VecDeque::new(),
+            inode_sorted: BTreeMap::new(),
             cursor: 0,
+            cursor_policy: CursorPolicy::Wrap,
             current_dir: None,
+            current_depth: 0,
+            noatime: false,
+            max_children: std::usize::MAX,
+            devices_seen: HashSet::new(),
+            children_read: 0,
+            file_types: Default::default(),
             inode_ordered: vec![],
             order: Dentries,
             phase: Phase::DirWalk,
+            phase_started: None,
             batch_size: 1024,
+            spill: None,
+            spill_threshold: 1 << 20,
+            spill_error: None,
+            content_trigger: Trigger::BatchSize,
+            first_batch_started: false,
+            skip_inodes: HashSet::new(),
+            statx_mask: 0,
+            created_after: None,
+            sort_strategy: SortStrategy::Stable,
+            single_sweep: false,
+            streaming: false,
+            skip_replaced_dirs: false,
+            skip_marker: None,
+            yield_denied_dirs: false,
+            max_path_depth: None,
+            yield_root: false,
+            revalidate: false,
+            measure_residency: false,
+            verify_ordering: false,
+            last_key: None,
             prefilter: None,
+            descend_filter: None,
+            mount_filter: None,
+            mount_table: HashMap::new(),
+            entry_filter: None,
+            case_insensitive: false,
+            seek_callback: None,
             prefetched: Default::default(),
             mountpoints: vec![],
-            prefetch_cap: 0
+            prefetch_devices: HashSet::new(),
+            prefetch_cap: 2,
+            prefetch_cap_bounds: (2, 2048),
+            prefetch_cap_policy: CapPolicy::Reset,
+            prefetcher: None,
+            prefetch_filter: None,
+            min_fadvise_bytes: 0,
+            max_fadvise_chunk: 32*1024*1024,
+            last_prefetch_ranges: vec![],
+            readahead_sizes: HashMap::new(),
+            prefetch_expiry: None,
+            prefetch_dir_blocks: true,
+            device_map: None,
+            max_cache_pressure: None,
+            max_bytes: None,
+            yielded_bytes: 0,
+            resident_bytes: 0,
+            reading: None,
+            stats: Default::default(),
+            progress: None,
+            last_progress: None,
+            warn_missing_extents: false,
+            trace: None,
+            visited: None,
         }
     }
 
@@ -130,7 +1010,42 @@ impl ToScan {
         self
     }
 
+    /// Number of queued entries currently prefetched ahead, adapted as prefetched entries are hit or missed
+    pub fn prefetch_cap(&self) -> usize {
+        self.prefetch_cap
+    }
+
+    /// Limits for `prefetch_cap`, defaults to 2 and 2048. Equal bounds pin it to a fixed depth.
+    pub fn set_prefetch_cap_bounds(&mut self, min: usize, max: usize) {
+        assert!(min <= max, "prefetch cap bounds are inverted");
+        self.prefetch_cap_bounds = (min, max);
+        self.prefetch_cap = std::cmp::max(min, std::cmp::min(max, self.prefetch_cap));
+    }
+
+    /// Defaults to `CapPolicy::Reset`, which reacts to a single miss by starting over from the minimum.
+    /// On workloads where that makes the prefetch depth oscillate, `CapPolicy::Halve` or `CapPolicy::Linear` back off more gently.
+    pub fn set_prefetch_cap_policy(&mut self, policy: CapPolicy) {
+        self.prefetch_cap_policy = policy;
+    }
+
+    /// Replaces the built-in advises with a custom prefetch mechanism, e.g. a userspace block cache.
+    /// Before each queued entry is taken the callback is handed the entries that come next, in the order they will
+    /// be visited and up to the upper bound of `set_prefetch_cap_bounds`, with their extents available via `Entry::extents`.
+    /// Consecutive calls mostly see the same entries again, keeping track of what was already fetched is up to the callback.
+    /// Works without `prefetch_dirs`, the adaptive cap and `set_max_cache_pressure` don't apply.
+    pub fn set_prefetcher(&mut self, prefetcher: Box<FnMut(&[&Entry<T>])>) {
+        self.prefetcher = Some(prefetcher);
+    }
+
+    /// Entries rejected by the filter are never advised and don't take up room in the prefetch window, e.g. files
+    /// the consumer is going to skip anyway. They are still yielded. Also applies to the entries handed to `set_prefetcher`.
+    pub fn set_prefetch_filter(&mut self, filter: Box<Fn(&Entry<T>) -> bool>) {
+        self.prefetch_filter = Some(filter);
+    }
+
     pub fn prefetch_dirs(&mut self, val: bool) {
+        self.prefetch_devices.clear();
+
         if !val {
             self.mountpoints = vec![];
             return;
@@ -142,29 +1057,1135 @@ impl ToScan {
                 self.mountpoints = vec![];
                 return
             }
-        }.filter_map(|e| e.ok()).collect();
+        }.filter_map(|e| e.ok()).collect();
+
+        // virtual and network filesystems have specs that aren't device nodes.
+        // their mounts still have to stay in the list so that paths below them aren't attributed to a parent mount
+        self.prefetch_devices = self.mountpoints.iter()
+            .filter(|m| metadata(&m.spec).map(|meta| meta.file_type().is_block_device()).unwrap_or(false))
+            .map(|m| m.spec.clone())
+            .collect();
+    }
+
+    /// Whether the extents of queued directories are advised, which is what speeds up the directory walk itself.
+    /// On by default once `prefetch_dirs` is enabled. Turning it off leaves only the files queued
+    /// by single sweep mode to be prefetched.
+    pub fn set_prefetch_dir_blocks(&mut self, val: bool) {
+        self.prefetch_dir_blocks = val;
+    }
+
+    /// Translates a physical offset as reported by FIEMAP into the device and offset that prefetches are issued against,
+    /// for stacked storage where the mount's device is not the one that should receive the advises.
+    /// Extents are translated by their start, so the mapping should not split extents.
+    /// With a map set filesystems whose offsets aren't plain device offsets, e.g. btrfs, are prefetched as well.
+    ///
+    /// The content pass also sweeps one device after the other by the translated offsets instead of ordering
+    /// by FIEMAP's offsets, which on a multi-device btrfs live in a logical address space spanning all disks.
+    /// Each file is placed on the device holding its start, see `Entry::primary_device`. FIEMAP doesn't report
+    /// the device itself, so for btrfs the map has to be built from the chunk tree, e.g. with `btrfs inspect-internal dump-tree -t chunk`.
+    /// Directory order, single sweep mode and `set_pipeline_distance` keep using the untranslated offsets.
+    pub fn set_device_map(&mut self, map: Box<Fn(u64) -> (PathBuf, u64)>) {
+        self.device_map = Some(map);
+    }
+
+    /// Extent runs smaller than this are merged with the following runs (and the gaps between them)
+    /// until the accumulated range reaches the threshold or a gap of at least that size is encountered.
+    /// Trades prefetch precision for fewer `posix_fadvise` calls on trees of small, scattered files.
+    /// Defaults to 0, i.e. every coalesced run is advised individually.
+    pub fn set_min_fadvise_bytes(&mut self, bytes: u64) {
+        self.min_fadvise_bytes = bytes;
+    }
+
+    /// Upper bound for the range passed to a single `posix_fadvise` call.
+    /// Larger coalesced runs are split into consecutive chunks so the kernel doesn't clamp or drop the hint.
+    /// Defaults to 32MiB, 0 disables splitting.
+    pub fn set_max_fadvise_chunk(&mut self, bytes: u64) {
+        self.max_fadvise_chunk = bytes;
+    }
+
+    /// Open directories with `O_NOATIME` so that the walk does not cause atime writes competing with the reads.
+    /// The kernel only honors this for files owned by the process or when running as root, others are opened normally.
+    /// Also applies to the files opened by `into_content_reader`, so reading from them doesn't update their atime either,
+    /// and to those opened for `set_measure_cache_residency`.
+    pub fn set_noatime(&mut self, val: bool) {
+        self.noatime = val;
+    }
+
+    /// Retry reading directories and determining file types up to `max_attempts` times in total when they fail
+    /// with errors that may go away, i.e. EIO, EAGAIN and ETIMEDOUT. The wait starts at `backoff` and doubles with every attempt.
+    /// Other errors are reported right away. Extent lookups are not retried since their errors carry no errno,
+    /// a failed lookup only costs the entry its place in the physical order anyway.
+    pub fn set_retry(&mut self, max_attempts: u32, backoff: Duration) {
+        self.retry = Some((max_attempts, backoff));
+    }
+
+    /// Restricts the thread that drives the walk to the given CPUs, e.g. the ones on the NUMA node of the storage controller.
+    /// Applied once by the first call to `next`, on whichever thread that happens.
+    /// Failures are ignored and it does nothing on platforms other than Linux.
+    pub fn set_cpu_affinity(&mut self, cpus: Vec<usize>) {
+        self.cpu_affinity = Some(cpus);
+    }
+
+    /// Stop reading a directory after this many entries, which bounds what a single pathological directory
+    /// can add to the queues and batches. The remaining entries are skipped and a `WalkError` is yielded
+    /// for the truncated directory. Unbounded by default.
+    pub fn set_max_children_per_dir(&mut self, children: usize) {
+        self.max_children = children;
+    }
+
+    /// Advised entries scheduled more than `distance` bytes behind the cursor stop counting against
+    /// the prefetch window. They won't be reached before the cursor wraps around, so the budget is better
+    /// spent on what's ahead. If they are still queued they get advised again later.
+    /// Disabled by default.
+    pub fn set_prefetch_expiry(&mut self, distance: u64) {
+        self.prefetch_expiry = Some(distance);
+    }
+
+    /// Keeps the device data advised by the walker under roughly `bytes`.
+    /// Prefetched entries are dropped from the page cache again once the walk has moved past them,
+    /// i.e. when the next entry is taken off the queue, as are advises that were evicted or expired before being used.
+    /// Prefetching stalls while the ceiling is reached. Disabled by default.
+    pub fn set_max_cache_pressure(&mut self, bytes: u64) {
+        self.max_cache_pressure = Some(bytes);
+    }
+
+    /// Ends the walk before the first entry that would take the extent bytes of all yielded entries past `bytes`,
+    /// e.g. to take the first 50GiB in read order. Only entries whose extents were looked up count, i.e. files in the
+    /// physical orders. Prefetching stays within what's left of the budget and reaching it works like `finish`.
+    pub fn set_max_bytes(&mut self, bytes: u64) {
+        self.max_bytes = Some(bytes);
+    }
+
+    pub fn set_prefilter(&mut self, filter: Box<Fn(&Path, &FileType) -> bool>) {
+        self.prefilter = Some(filter)
+    }
+
+    /// Directories for which the filter returns false are not descended into.
+    /// Unlike the prefilter this prunes whole subtrees, it does not affect whether the directory itself is yielded.
+    pub fn set_descend_filter(&mut self, filter: Box<Fn(&Path, &FileType) -> bool>) {
+        self.descend_filter = Some(filter)
+    }
+
+    /// Consulted for directories that are mount points, which are only descended into if it returns true.
+    /// The mount point itself is still yielded. The mount table is read when the filter is set,
+    /// matching is done on the path as walked, so this only works for absolute roots.
+    pub fn set_mount_filter(&mut self, filter: Box<Fn(&mnt::MountEntry) -> bool>) -> std::io::Result<()> {
+        self.mount_table = mnt::MountIter::new_from_proc()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            .filter_map(|m| m.ok())
+            .map(|m| (m.file.clone(), m))
+            .collect();
+        self.mount_filter = Some(filter);
+        Ok(())
+    }
+
+    /// Decides about yielding and descending in a single call, similar to walkdir's `filter_entry`
+    /// except that a directory can be suppressed while its contents are still visited.
+    /// Applies in addition to the prefilter and descend filter, an entry has to pass all of them.
+    pub fn set_entry_filter(&mut self, filter: Box<Fn(&Path, &FileType) -> FilterDecision>) {
+        self.entry_filter = Some(filter)
+    }
+
+    /// Hand lowercased paths to the prefilter and descend filter, for trees on case-folding filesystems
+    /// or destined for one. Matching against the folded form means a filter written for `foo.JPG` catches
+    /// `Foo.jpg` no matter which case the filesystem reports. Yielded entries keep their on-disk path.
+    pub fn set_case_insensitive(&mut self, val: bool) {
+        self.case_insensitive = val;
+    }
+
+    /// Called with `(from_offset, to_offset)` whenever the directory cursor wraps around
+    /// to pick up entries behind the current physical position.
+    /// Useful to count how many full-disk rewinds a tree causes.
+    pub fn set_seek_callback(&mut self, cb: Box<FnMut(u64, u64)>) {
+        self.seek_callback = Some(cb)
+    }
+
+    /// Only affects `Order::Content`. Instead of collecting files and sorting them in a separate pass
+    /// they are scheduled by physical offset alongside the directories that are still to be visited,
+    /// so directory descent and file contents are covered in a single sweep over the disk.
+    /// Files get yielded when the cursor reaches them, directories are yielded as soon as they are discovered.
+    pub fn set_single_sweep(&mut self, val: bool) {
+        self.single_sweep = val;
+    }
+
+    /// Only affects `Order::Content`. Instead of collecting batches and ordering them in separate passes, the extents
+    /// of each entry are looked up as it is discovered and it is yielded once the directory cursor has moved
+    /// `distance` bytes past it, so contents are read in the wake of discovery during a single sweep.
+    /// Entries discovered behind that point are yielded right away, everything left is yielded in order once
+    /// discovery is done. Entries waiting for the cursor are not bounded by the batch size, and
+    /// `set_verify_ordering` only checks the final flush. Single sweep mode takes precedence.
+    pub fn set_pipeline_distance(&mut self, distance: u64) {
+        self.pipeline_distance = Some(distance);
+    }
+
+    /// Development aid that panics as soon as an entry is yielded out of order.
+    ///
+    /// The ordering contract only holds within one batch (see `set_batchsize`):
+    ///
+    /// * `Order::Inode` yields ascending inode numbers
+    /// * `Order::Content` yields ascending offsets of the lowest physical extent,
+    ///   entries without extents are placed at offset 0 and thus come first
+    /// * in single sweep mode files are yielded at ascending offsets between two rewinds of the cursor,
+    ///   files without extents are yielded as discovered and not checked
+    ///
+    /// `Order::Dentries` and `Order::ReaddirNatural` make no promises.
+    pub fn set_verify_ordering(&mut self, val: bool) {
+        self.verify_ordering = val;
+    }
+
+    /// Only affects `Order::Content`. When a new batch starts more than `bytes` behind the position where
+    /// the previous one ended, its files near or ahead of that position are yielded first and the ones further back
+    /// only afterwards, so the first step of a batch does not seek all the way back.
+    /// Trades strict ascending order within a batch for smaller individual seeks.
+    pub fn set_max_seek_gap(&mut self, bytes: u64) {
+        self.max_seek_gap = Some(bytes);
+    }
+
+    /// Orders each content batch by `inode_weight * inode + offset_weight * offset` instead of the offset alone,
+    /// with both normalized to the largest value in the batch. For devices where neither predicts seek costs well,
+    /// the weights can be tuned empirically. Files without extents keep their placement by the unknown offset policy.
+    /// Doesn't apply to `Order::RotationalOptimal`, disables `set_max_seek_gap` since keys are no longer offsets.
+    pub fn set_combined_key(&mut self, inode_weight: f64, offset_weight: f64) {
+        assert!(inode_weight >= 0.0 && offset_weight >= 0.0 && inode_weight + offset_weight > 0.0, "weights must be non-negative and not both zero");
+        self.combined_key = Some((inode_weight, offset_weight));
+    }
+
+    /// Order in which the inode pass processes a batch, i.e. the order entries are yielded in `Order::Inode`
+    /// and the order their metadata and extents are looked up in `Order::Content`. Defaults to `Direction::Ascending`,
+    /// descending order is mostly useful to measure whether inode order makes a difference on a given filesystem.
+    pub fn set_inode_direction(&mut self, direction: Direction) {
+        self.inode_direction = direction;
+    }
+
+    /// Where `Order::Content` puts files for which no extents could be found. Defaults to `UnknownOffsetPolicy::First`
+    pub fn set_unknown_offset_policy(&mut self, policy: UnknownOffsetPolicy) {
+        self.unknown_offset_policy = policy;
+    }
+
+    /// Groups the files of each content pass batch by size, in buckets growing by a factor of 16, and sweeps
+    /// the buckets one after another in the given order, each by physical offset. Useful for fast perceived progress
+    /// when small files are cheap to process. Sizes are the sum of the extent lengths, so files without extents
+    /// have no bucket and come before or after all of them according to the unknown offset policy.
+    ///
+    /// Every bucket is a separate sweep across the disk, so this trades seek efficiency for the size order:
+    /// with `SmallFirst` a batch typically costs several sweeps instead of one. Larger batches mean longer sweeps.
+    pub fn set_size_priority(&mut self, priority: SizePriority) {
+        self.size_priority = priority;
+    }
+
+    /// Defaults to `DirKey::DataExtent`. Directory extents are still looked up for prefetching with `DirKey::Inode`,
+    /// which however makes `progress_fraction` meaningless. Ignored in single sweep mode and `Order::Readiness`
+    /// since files share the queue with directories there and are always keyed by physical offset.
+    pub fn set_dir_sort_key(&mut self, key: DirKey) {
+        self.dir_sort_key = key;
+    }
+
+    /// Enables `Order::RotationalOptimal`. Physical offsets are taken as byte offsets on the device,
+    /// so this is only meaningful for filesystems on a raw disk or a partition starting on a track boundary.
+    pub fn set_geometry(&mut self, geometry: Geometry) {
+        self.geometry = Some(geometry);
+    }
+
+    /// Defaults to `CursorPolicy::Wrap`
+    pub fn set_cursor_policy(&mut self, policy: CursorPolicy) {
+        self.cursor_policy = policy;
+    }
+
+    /// `(dev, ino)` pairs as returned by `Entry::dev` and `Entry::ino` which will not be yielded,
+    /// e.g. files already processed by a previous run. Consulted for files in every order except `Order::Dentries`
+    /// and `Order::ReaddirNatural`, matching entries are dropped before they are scheduled so their extents are
+    /// never looked up. Directories are still descended into.
+    pub fn set_skip_inodes(&mut self, inodes: HashSet<(u64, u64)>) {
+        self.skip_inodes = inodes;
+    }
+
+    /// `STATX_*` flags of the fields to fetch for each file in `Order::Inode` and `Order::Content`.
+    /// The calls happen while entries are processed in inode order which keeps inode table reads sequential,
+    /// results are available via `Entry::statx`. Requesting only what is needed can spare the filesystem some work.
+    /// Defaults to 0, which skips the calls.
+    pub fn set_statx_mask(&mut self, mask: u32) {
+        self.statx_mask = mask;
+    }
+
+    /// Convenience over `set_statx_mask` for the common fields, which are then available through
+    /// `Entry::size`, `Entry::mtime` and friends. Like the mask this only applies to the inode and content passes.
+    /// Replaces any previously set mask.
+    pub fn set_required_metadata(&mut self, fields: MetaFields) {
+        self.statx_mask = fields.statx_mask();
+    }
+
+    /// How batches are sorted by inode and `into_per_device` sorts each device's entries, defaults to `SortStrategy::Stable`.
+    /// The content pass orders by offset through a heap, which works in place either way.
+    pub fn set_sort_strategy(&mut self, strategy: SortStrategy) {
+        self.sort_strategy = strategy;
+    }
+
+    /// Drops entries created before `cutoff` according to their birth time, which also catches files recreated in place
+    /// whose mtime was preserved. Checked for files in every order except `Order::Dentries` and `Order::ReaddirNatural`,
+    /// directories are still descended into. Entries without a recorded birth time are kept.
+    pub fn set_created_after(&mut self, cutoff: SystemTime) {
+        self.created_after = Some(cutoff);
+    }
+
+    /// The statx fields fetched in the inode and content passes
+    fn fetch_mask(&self) -> u32 {
+        match self.created_after {
+            Some(_) => self.statx_mask | libc::STATX_BTIME,
+            None => self.statx_mask
+        }
+    }
+
+    /// On a live filesystem a directory may be replaced by a file between being queued and being read.
+    /// By default the resulting ENOTDIR is yielded as an error like any other, with this set it is
+    /// only counted in `Stats::replaced_dirs` and the walk moves on.
+    pub fn set_skip_replaced_dirs(&mut self, val: bool) {
+        self.skip_replaced_dirs = val;
+    }
+
+    /// Skip the whole subtree of any directory containing a file or directory with this name, e.g. `.noindex`.
+    /// None of its contents are yielded or descended into, the directory itself still is if its parent listed it.
+    /// Combine with the prefilter to also restrict the walk to certain extensions.
+    ///
+    /// Rather than enumerating the directory up front, the marker is looked up by name right before the
+    /// directory is read. That's one extra lookup per directory, which mostly hits the directory blocks
+    /// that are about to be read anyway. The check happens when the directory comes up in the schedule,
+    /// not when its parent lists it, so marked directories keep their place among their siblings
+    /// and still cost the seek to reach them.
+    pub fn set_skip_marker(&mut self, filename: OsString) {
+        self.skip_marker = Some(filename);
+    }
+
+    /// Yield directories that can't be read due to EACCES as an entry flagged with `Entry::descend_failed`
+    /// instead of an error, so that consumers can tell inaccessible directories from empty ones.
+    /// The placeholder comes in addition to the entry the parent's listing produced, if any.
+    pub fn set_yield_denied_dirs(&mut self, val: bool) {
+        self.yield_denied_dirs = val;
+    }
+
+    /// Don't list directories whose contents would be more than `depth` levels below their root, yield an error
+    /// for each of them instead. A safeguard against adversarial trees on untrusted filesystems. The walk itself holds
+    /// no per-level state, queued entries only carry their depth, so deep trees just cost their path lengths.
+    pub fn set_max_path_depth(&mut self, depth: usize) {
+        self.max_path_depth = Some(depth);
+    }
+
+    /// Also yield directory roots themselves, they are only walked by default. File roots are always yielded.
+    /// A root is emitted when it gets opened: right away for `Order::Dentries`, `Order::ReaddirNatural` and `Order::DirsThenFiles`,
+    /// otherwise it joins the current batch like any of its children, so in `Order::Content` its place is
+    /// determined by the extents of its directory blocks. Requeued directories count as roots.
+    pub fn set_yield_root(&mut self, val: bool) {
+        self.yield_root = val;
+    }
+
+    /// Entries held back for the inode and content passes can be deleted or replaced before they are yielded.
+    /// With this set each of them is looked up again right before it is yielded and an error is yielded in its place
+    /// if it is gone or now refers to a different inode. Costs an lstat per entry.
+    pub fn set_revalidate_on_yield(&mut self, val: bool) {
+        self.revalidate = val;
+    }
+
+    /// Makes sure a buffered entry still refers to what was discovered, see `set_revalidate_on_yield`
+    fn revalidate(&self, e: Entry<T>) -> Result<Entry<T>, WalkError> {
+        if !self.revalidate {
+            return Ok(e);
+        }
+        match self.backend.stat(e.base_fd(), &e.path, false) {
+            Ok(ref st) if st.ino == e.ino => Ok(e),
+            Ok(_) => Err(WalkError::new(e.path, std::io::Error::new(std::io::ErrorKind::Other, "replaced since it was discovered"))),
+            Err(err) => Err(WalkError::new(e.path, err))
+        }
+    }
+
+    /// Check how much of each content ordered file is already in the page cache when it is yielded,
+    /// which shows whether the ordering and prefetching actually pay off. Costs a mapping and a mincore call per file.
+    /// Results go into `Stats::pages_checked` and `Stats::pages_resident`.
+    pub fn set_measure_cache_residency(&mut self, val: bool) {
+        self.measure_residency = val;
+    }
+
+    pub fn set_batchsize(&mut self, batch: usize) {
+        self.batch_size = batch;
+    }
+
+    /// Number of files buffered for the inode pass at which they get written to a run, see `ToScan::set_spill_dir`.
+    /// Defaults to 2^20. Has no effect if it's not below the batch size.
+    pub fn set_spill_threshold(&mut self, entries: usize) {
+        self.spill_threshold = entries;
+    }
+
+    /// Lets the first batch start early to yield something sooner, all later batches are collected up to the batch size.
+    /// Defaults to `Trigger::BatchSize`. Has no effect on `Order::DirsThenFiles` outside of streaming mode.
+    pub fn set_content_trigger(&mut self, trigger: Trigger) {
+        self.content_trigger = trigger;
+    }
+
+    /// Bounds the number of files held for ordering to `chunk_files`, at the price of
+    /// only ordering within each chunk. Files are flushed through the inode and content passes whenever a chunk
+    /// is full, which also applies to `Order::DirsThenFiles`, so directories and files end up interleaved chunk by chunk.
+    /// Single sweep mode is disabled since it holds every discovered file until the cursor reaches it,
+    /// `Order::Readiness` works the same way and is not bounded.
+    /// Only queued directories remain unbounded, which is a small fraction of the tree for most filesystems.
+    pub fn set_streaming_mode(&mut self, chunk_files: usize) {
+        self.batch_size = std::cmp::max(1, chunk_files);
+        self.streaming = true;
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Keep track of the `top_n` most fragmented entries among those whose extents are looked up for ordering,
+    /// i.e. everything yielded in the content pass or in single sweep mode. Directories can be fragmented too.
+    pub fn set_track_fragmentation(&mut self, top_n: usize) {
+        self.analysis.fragmentation = Some(Fragmentation { top_n: top_n, worst: vec![] });
+    }
+
+    /// Paths and number of physically discontiguous fragments of the most fragmented files so far,
+    /// most fragmented first. Entries in a single piece are never listed. Empty unless enabled via `set_track_fragmentation`.
+    pub fn most_fragmented(&self) -> &[(PathBuf, usize)] {
+        match self.analysis.fragmentation {
+            Some(ref f) => &f.worst,
+            None => &[]
+        }
+    }
+
+    /// Remember the extents of every entry whose extents are looked up for ordering, see `set_track_fragmentation`
+    /// for which ones those are. This holds all of them in memory until the walk is dropped.
+    pub fn set_track_shared_extents(&mut self, val: bool) {
+        self.analysis.shared = if val { Some(Default::default()) } else { None };
+    }
+
+    /// Groups of paths whose extents overlap physically on the same filesystem, e.g. reflinked copies or
+    /// deduplicated files. Hard links show up as well since they are the same file under several paths.
+    /// Empty unless enabled via `set_track_shared_extents`.
+    pub fn shared_extent_groups(&self) -> Vec<Vec<PathBuf>> {
+        match self.analysis.shared {
+            Some(ref shared) => shared.groups(),
+            None => vec![]
+        }
+    }
+
+    /// `(device, offset, length)` of the coalesced runs advised by the most recent prefetch round,
+    /// before they are split into `set_max_fadvise_chunk` sized calls. Runs on block devices are rounded out
+    /// to the device's readahead window (`read_ahead_kb`) so the kernel reads whole windows instead of single blocks.
+    /// Rounds skipped because the window was still full leave this untouched.
+    pub fn last_prefetch_ranges(&self) -> &[(PathBuf, u64, u64)] {
+        &self.last_prefetch_ranges
+    }
+
+    /// Calls `callback` with the current stats at most every `interval` while the walk is making progress,
+    /// including during the inode and content passes where a single call to `next` can take a long time.
+    /// Checked after every directory entry and every part of a batch, so it fires late rather than early.
+    pub fn set_progress_interval(&mut self, interval: Duration, callback: Box<FnMut(&Stats)>) {
+        self.progress = Some((interval, callback));
+        self.last_progress = None;
+    }
+
+    fn report_progress(&mut self) {
+        if let Some((interval, ref mut callback)) = self.progress {
+            let now = Instant::now();
+            match self.last_progress {
+                Some(last) if now.duration_since(last) < interval => {}
+                Some(_) => {
+                    callback(&self.stats);
+                    self.last_progress = Some(now);
+                }
+                // the first call only starts the clock
+                None => self.last_progress = Some(now)
+            }
+        }
+    }
+
+    /// Print a one-time warning to stderr once most files turn out to have no extents,
+    /// see `Stats::files_without_extents`
+    pub fn set_warn_missing_extents(&mut self, val: bool) {
+        self.warn_missing_extents = val;
+    }
+
+    /// Records every yielded entry and every advised range from here on, see `take_trace`
+    pub fn record(&mut self) {
+        if self.trace.is_none() {
+            self.trace = Some(WalkTrace::default());
+        }
+    }
+
+    /// Stops recording and returns what has been recorded so far.
+    /// Replaying it re-issues the same advises interleaved with the same paths, which makes it possible
+    /// to tell a regression in the scheduling apart from a change of the scanned tree.
+    pub fn take_trace(&mut self) -> Option<WalkTrace> {
+        self.trace.take()
+    }
+
+    /// Remembers `(dev, ino, path)` of every yielded entry from here on, see `take_visited`
+    pub fn collect_visited(&mut self) {
+        if self.visited.is_none() {
+            self.visited = Some(vec![]);
+        }
+    }
+
+    /// Stops collecting and returns what has been yielded so far.
+    /// After a full walk this is the set of entries an external watcher would have to cover.
+    pub fn take_visited(&mut self) -> Option<Vec<(u64, u64, PathBuf)>> {
+        self.visited.take()
+    }
+
+    fn check_extent_coverage(&mut self) {
+        if !self.warn_missing_extents || self.stats.files_mapped < 64 {
+            return;
+        }
+        if self.stats.files_without_extents * 2 > self.stats.files_mapped {
+            eprintln!("platter-walk: {} of {} files have no extents, content ordering is ineffective on this filesystem",
+                      self.stats.files_without_extents, self.stats.files_mapped);
+            self.warn_missing_extents = false;
+        }
+    }
+
+    /// Books the time since the last switch on the phase that is being left
+    fn set_phase(&mut self, phase: Phase) {
+        let now = Instant::now();
+        if let Some(started) = self.phase_started {
+            let spent = now.duration_since(started);
+            match self.phase {
+                Phase::DirWalk => self.stats.dir_walk_time += spent,
+                Phase::InodePass => self.stats.inode_pass_time += spent,
+                Phase::ContentPass => self.stats.content_pass_time += spent
+            }
+        }
+        self.phase_started = Some(now);
+        self.phase = phase;
+    }
+
+    fn start_inode_pass(&mut self) {
+        self.first_batch_started = true;
+        self.set_phase(Phase::InodePass);
+        self.last_key = None;
+        self.sort_inode_ordered();
+    }
+
+    fn sort_inode_ordered(&mut self) {
+        // reverse sort so we can pop
+        match self.inode_direction {
+            Direction::Ascending => self.sort_strategy.sort(&mut self.inode_ordered, |dent| std::u64::MAX - dent.ino()),
+            Direction::Descending => self.sort_strategy.sort(&mut self.inode_ordered, |dent| dent.ino())
+        }
+    }
+
+    /// Moves the sorted batch to the content pass queue at the offsets of the files' lowest extents
+    fn locate_leaves(&mut self) {
+        // hand over in chunks and release the drained part of the batch as we go
+        // instead of holding every leaf twice until the whole batch has moved
+        let chunk = std::cmp::max(1, self.inode_ordered.len() / 4);
+        let statx_mask = self.fetch_mask();
+        let created_after = self.created_after;
+        let unknown_offset = self.unknown_offset_policy;
+        let size_priority = self.size_priority;
+        while !self.inode_ordered.is_empty() {
+            let keep = self.inode_ordered.len().saturating_sub(chunk);
+            // tail holds the next inodes, so this still looks them up in the configured direction
+            while self.inode_ordered.len() > keep {
+                let mut e = self.inode_ordered.pop().unwrap();
+                let extents = file_extents(&*self.backend, e.base_fd(), e.path(), e.dev, &mut self.stats, &mut self.analysis);
+                let (offset, order) = match self.place(&extents) {
+                    Some((offset, rank, device)) => {
+                        e.device_rank = rank;
+                        e.primary_device = device;
+                        e.located_bytes = extents.iter().map(|x| x.length).sum();
+                        e.size_bucket = size_priority.bucket(e.located_bytes);
+                        (offset, Content)
+                    }
+                    None => match unknown_offset.placement() {
+                        Some((offset, bucket, rank)) => {
+                            e.size_bucket = bucket;
+                            e.device_rank = rank;
+                            (offset, Inode)
+                        }
+                        None => continue
+                    }
+                };
+                let e = e.with_order(order).fetch_statx(statx_mask);
+                if e.created_before(created_after) {
+                    continue;
+                }
+                self.phy_sorted_leaves.push(Leaf(offset, e));
+            }
+            self.inode_ordered.shrink_to_fit();
+            self.report_progress();
+        }
+    }
+
+    /// Where the content pass puts a file: the lowest physical offset, or with a device map the lowest translated offset
+    /// on the device holding the start of the file along with that device and its rank
+    fn place(&mut self, extents: &[FileExtent]) -> Option<(u64, u32, Option<Arc<PathBuf>>)> {
+        let (device, offset) = {
+            let map = match self.device_map {
+                Some(ref map) => map,
+                None => return lowest_physical(extents).map(|offset| (offset, 0, None))
+            };
+            let first = extents.iter().min_by_key(|e| e.logical)?;
+            let (device, _) = map(first.physical);
+            let offset = extents.iter().map(|e| map(e.physical)).filter(|&(ref d, _)| *d == device).map(|(_, o)| o).min()?;
+            (device, offset)
+        };
+        // rank 0 is left to files without extents
+        let next_rank = self.device_ranks.len() as u32 + 1;
+        let &mut (rank, ref path) = self.device_ranks.entry(device.clone()).or_insert_with(|| (next_rank, Arc::new(device)));
+        Some((offset, rank, Some(path.clone())))
+    }
+
+    /// Whether buffered files are written out, see `set_spill_dir`
+    fn spills(&self) -> bool {
+        let order = match self.order {
+            Content | RotationalOptimal | DirsThenFiles => true,
+            _ => false
+        };
+        order && self.spill.is_some() && self.pipeline_distance.is_none() && self.combined_key.is_none() && self.device_map.is_none()
+    }
+
+    fn spill_pending(&self) -> usize {
+        self.spill.as_ref().map(|s| s.pending()).unwrap_or(0)
+    }
+
+    /// Looks up the buffered files like the inode pass would and writes them out as a sorted run
+    fn spill_batch(&mut self) -> Result<(), WalkError> {
+        self.sort_inode_ordered();
+        self.locate_leaves();
+        // heap order is reversed, so the sorted vec starts with the highest offset
+        let mut leaves = std::mem::replace(&mut self.phy_sorted_leaves, BinaryHeap::new()).into_sorted_vec();
+        leaves.reverse();
+        let spill = self.spill.as_mut().unwrap();
+        match spill.write_run(&leaves) {
+            Ok(()) => {
+                self.stats.spilled_entries += leaves.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                self.spill_threshold = std::usize::MAX;
+                self.phy_sorted_leaves.extend(leaves);
+                Err(WalkError::new(spill.dir.clone(), e))
+            }
+        }
+    }
+
+    /// Loads spilled leaves until the lowest one in memory is the lowest overall
+    fn refill_leaves(&mut self) {
+        let mask = self.fetch_mask();
+        if let Some(ref mut spill) = self.spill {
+            if let Err(e) = spill.refill(&mut self.phy_sorted_leaves, mask) {
+                self.spill_error = Some(WalkError::new(spill.dir.clone(), e));
+            }
+        }
+    }
+
+    /// Whether files are scheduled alongside directories instead of going through the inode and content passes
+    fn sweeps_files(&self) -> bool {
+        self.order == Readiness || (self.order == Content && self.single_sweep && !self.streaming)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.phy_sorted.is_empty() && self.unordered.is_empty() && self.inode_sorted.is_empty() && self.current_dir.is_none()
+    }
+
+    pub fn add_root(&mut self, path : PathBuf) -> std::io::Result<()> where T: Default {
+        self.add_root_tagged(path, T::default())
+    }
+
+    /// Everything found below the root will carry `tag`, see `Entry::tag`
+    pub fn add_root_tagged(&mut self, path : PathBuf, tag: T) -> std::io::Result<()> {
+        let meta = self.backend.stat(None, &path, true)?;
+        if self.roots.is_empty() {
+            self.device_size = block_device_size(&path);
+        }
+        if self.confine_roots && meta.ftype.is_dir() {
+            self.add_fd_root(File::open(&path)?, tag)?;
+            self.roots.push(path);
+            return Ok(());
+        }
+        self.roots.push(path.clone());
+        self.add(Entry::with_tag(path, meta.ftype, meta.ino, vec![], tag), None);
+        Ok(())
+    }
+
+    /// Makes `add_root` open directory roots right away and walk them like `add_root_fd`, so the walk stays within
+    /// the tree the path referred to at that point even if it gets moved or replaced later.
+    /// Yielded paths are relative to their root as with `add_root_fd`, use tags to tell several roots apart.
+    pub fn set_confine_roots(&mut self, val: bool) {
+        self.confine_roots = val;
+    }
+
+    /// Walks the directory referred to by `fd` without ever resolving its path, everything below it
+    /// is opened with `openat` relative to the fd and lookups can't escape beneath it. Yielded paths are relative
+    /// to that directory and start with `.`. The fd is duplicated, the caller's copy can be closed right away
+    /// and yielded entries remain usable after the walker is gone.
+    /// Such roots can't be matched to a mount point, so no directory prefetching happens for them.
+    pub fn add_root_fd(&mut self, fd: RawFd) -> std::io::Result<()> where T: Default {
+        let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.add_fd_root(unsafe { File::from_raw_fd(dup) }, T::default())
+    }
+
+    fn add_fd_root(&mut self, dir: File, tag: T) -> std::io::Result<()> {
+        let path = PathBuf::from(".");
+        let meta = dir::symlink_metadata_at(Some(dir.as_raw_fd()), &path)?;
+        let mut root = Entry::with_tag(path, meta.file_type(), meta.ino(), vec![], tag);
+        root.base = Some(Arc::new(dir));
+        self.add(root, None);
+        Ok(())
+    }
+
+    /// Schedules a changed path to be scanned again, e.g. when a watcher reported modifications below it.
+    /// Directories are walked like a root, including physical ordering and prefetching, files are yielded on their own.
+    /// Also works after the walk was exhausted, iterating further then picks up the requeued paths.
+    /// Depths are relative to the requeued path.
+    pub fn requeue(&mut self, path: PathBuf) -> std::io::Result<()> where T: Default {
+        let meta = self.backend.stat(None, &path, false)?;
+        let extents = self.backend.extent_map(None, &path).unwrap_or(vec![]);
+        self.schedule(Entry::with_tag(path, meta.ftype, meta.ino, extents, T::default()));
+        Ok(())
+    }
+
+    /// Position of the cursor relative to the size of the block device backing the first root.
+    /// Since directories are visited in ascending physical order this approximates how much of the tree has been covered,
+    /// although wrap-arounds of the cursor make it jump back.
+    /// None if the size could not be determined, querying it requires read access to the device node.
+    pub fn progress_fraction(&self) -> Option<f64> {
+        self.device_size.map(|size| (self.cursor as f64 / size as f64).min(1.0))
+    }
+
+    /// Number of distinct devices among the directories read so far, e.g. to decide between a single content
+    /// ordered walk and one walker per disk. Counts filesystems rather than disks, so a filesystem spanning
+    /// several disks or several partitions of one disk are not told apart.
+    pub fn device_count(&self) -> usize {
+        self.devices_seen.len()
+    }
+
+    /// Physical offset of whatever the walk is going to visit next, without consuming it. Lets another component
+    /// accessing the same disk stay out of the way or follow the same sweep.
+    /// None if that isn't known: while a directory is being read, for queued entries without extents, for files
+    /// placed by the unknown offset policy, with `set_combined_key` or directories sorted by `DirKey::Inode`.
+    /// In the content pass `set_max_seek_gap` and `Order::RotationalOptimal` can still pick a different leaf,
+    /// with `set_device_map` the offset is the translated one on the leaf's primary device.
+    pub fn next_scheduled_offset(&self) -> Option<u64> {
+        let leaf_offset = |l: &Leaf<T>| if l.1.effective_order() == Content { Some(l.0) } else { None };
+        let blended = self.combined_key.is_some() && self.order != RotationalOptimal;
+
+        if self.phase == Phase::ContentPass || (self.phase == Phase::DirWalk && self.is_empty()) {
+            if blended {
+                return None;
+            }
+            return match self.phy_sorted_leaves.peek() {
+                Some(l) => leaf_offset(l),
+                None => self.deferred_leaves.iter().min_by_key(|l| l.key()).and_then(leaf_offset)
+            }
+        }
+
+        if self.phase != Phase::DirWalk {
+            return None;
+        }
+
+        if let Some(distance) = self.pipeline_distance {
+            if let Some(l) = self.phy_sorted_leaves.peek().filter(|l| l.0.saturating_add(distance) <= self.cursor) {
+                return leaf_offset(l);
+            }
+        }
+
+        if self.current_dir.is_some() || !self.unordered.is_empty() || !self.inode_sorted.is_empty() || self.order == Readiness {
+            return None;
+        }
+        // directories are keyed by inode instead
+        if self.dir_sort_key == DirKey::Inode && !self.sweeps_files() {
+            return None;
+        }
+
+        match self.phy_sorted.range(self.cursor..).next() {
+            Some((&k, _)) => Some(k),
+            None if self.cursor_policy == CursorPolicy::Wrap => self.phy_sorted.keys().next().cloned(),
+            None => None
+        }
+    }
+
+    /// Entries waiting to be visited or yielded across all internal queues, directories and files alike.
+    /// A growing number means discovery is outpacing consumption. Cheap enough to call for every entry.
+    pub fn queued_entries(&self) -> usize {
+        self.phy_sorted.len() + self.unordered.len() + self.inode_sorted.len() + self.inode_ordered.len()
+            + self.phy_sorted_leaves.len() + self.deferred_leaves.len() + self.spill_pending()
+    }
+
+    /// The paths successfully added via `add_root`, in insertion order
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Ends the current walk so the configuration can be reused with new roots.
+    /// Entries that were prefetched but never reached are dropped from the page cache again,
+    /// the prefetch window starts over and anything still queued is discarded. `stats` are kept.
+    /// Device handles are only held for the duration of a prefetch round, so none remain open afterwards.
+    pub fn finish(&mut self) {
+        let mut unused: HashMap<PathBuf, Vec<(u64, u64)>> = HashMap::new();
+        for (_, p) in self.prefetched.drain() {
+            for (device, offset, len) in p.ranges {
+                unused.entry(device).or_insert(vec![]).push((offset, len));
+            }
+        }
+        for (device, ranges) in unused {
+            if let Ok(f) = File::open(&device) {
+                for (offset, len) in ranges {
+                    fadvise_dontneed(&f, offset, len);
+                }
+            }
+        }
+        self.prefetch_cap = self.prefetch_cap_bounds.0;
+        self.last_prefetch_ranges.clear();
+        if let Some(p) = self.reading.take() {
+            self.release(p);
+        }
+        self.resident_bytes = 0;
+
+        self.roots.clear();
+        self.device_size = None;
+        self.first_batch_started = false;
+        self.phy_sorted.clear();
+        self.unordered.clear();
+        self.inode_sorted.clear();
+        self.current_dir = None;
+        self.inode_ordered.clear();
+        self.phy_sorted_leaves.clear();
+        self.deferred_leaves.clear();
+        if let Some(ref mut spill) = self.spill {
+            spill.clear();
+        }
+        self.spill_error = None;
+        self.set_phase(Phase::DirWalk);
+        self.phase_started = None;
+        self.cursor = 0;
+        self.content_pos = 0;
+        self.content_sweep = (0, 0);
+        self.last_key = None;
+        self.yielded_bytes = 0;
+    }
+
+    /// Drives the whole walk, pushing every entry into `tx`.
+    /// A bounded channel provides backpressure: when the consumer falls behind the walk blocks
+    /// and prefetching pauses with it. Returns early once the receiving side hangs up.
+    pub fn run(self, tx: SyncSender<std::io::Result<Entry<T>>>) {
+        for res in self {
+            if tx.send(res.map_err(std::io::Error::from)).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Drains the walk and returns all entries sorted lexically by path.
+    /// Mostly useful with `Order::Dentries` when the output has to be deterministic,
+    /// the physically ordered traversal still determines how fast the tree is read.
+    /// Stops at the first error. The same path can show up more than once if roots overlap,
+    /// ties are broken by `(dev, ino)`.
+    pub fn collect_sorted_by_path(mut self) -> Result<Vec<Entry<T>>, WalkError> {
+        let mut entries = Vec::new();
+        while let Some(e) = self.next() {
+            if entries.len() == entries.capacity() {
+                // whatever is queued will be yielded too
+                entries.reserve(self.queued_entries() + 1);
+            }
+            entries.push(e?);
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path).then((a.dev, a.ino).cmp(&(b.dev, b.ino))));
+        Ok(entries)
+    }
+
+    /// Yields every regular file below the roots, each path once, and nothing else apart from errors.
+    /// Files are in ascending physical order within each batch, files whose extents could not be determined
+    /// come at the end of their batch ordered by inode, see `set_batchsize` for the scope of the ordering.
+    /// Overrides the configured order, single sweep mode and unknown offset policy, filters still apply.
+    pub fn files_in_content_order(mut self) -> impl Iterator<Item=Result<Entry<T>, WalkError>> {
+        self.order = Content;
+        self.single_sweep = false;
+        self.unknown_offset_policy = UnknownOffsetPolicy::Last;
+        self.filter(|res| res.as_ref().map(|e| e.file_type().is_file()).unwrap_or(true))
+    }
+
+    /// Like `files_in_content_order`, but also opens the next `read_ahead` files and advises their whole contents
+    /// before they are handed out, so that reading each returned file from the start mostly hits the page cache.
+    /// That many files are held open at any time. Useful for consumers that read everything, e.g. to hash it.
+    pub fn into_content_reader(mut self, read_ahead: usize) -> ContentReader<T> {
+        self.order = Content;
+        self.single_sweep = false;
+        self.unknown_offset_policy = UnknownOffsetPolicy::Last;
+        ContentReader::new(self, read_ahead)
+    }
+
+    /// Only the directory skeleton of the tree, each directory with the index of its parent in the returned list,
+    /// None for roots. Files are neither yielded nor looked at beyond what listing their directories costs.
+    /// Directories are in the order they were found, which follows the physical order their parents were read in,
+    /// so a parent always comes before its children. Stops at the first error.
+    pub fn scan_dirs_only(mut self) -> std::io::Result<Vec<(PathBuf, Option<usize>)>> {
+        self.order = Dentries;
+        self.yield_root = true;
+        let mut dirs = vec![];
+        let mut index : HashMap<PathBuf, usize> = HashMap::new();
+
+        for e in self.by_ref() {
+            let e = e?;
+            if !e.file_type().is_dir() {
+                continue;
+            }
+            let parent = e.path().parent().and_then(|p| index.get(p).cloned());
+            index.insert(e.path.clone(), dirs.len());
+            dirs.push((e.path, parent));
+        }
+
+        Ok(dirs)
+    }
+
+    /// Splits the walk by device, e.g. to hand each disk to its own thread.
+    /// Device membership is only known once entries have been discovered, so this drains the whole walk first
+    /// and holds every entry in memory. The entries of each device keep the order the walk produced them in,
+    /// in `Order::Inode` and `Order::Content` they are additionally sorted across batches, giving one sweep per device.
+    /// Devices are identified by `Entry::dev`, errors are returned separately since they can't be attributed to one.
+    /// Nothing is prefetched, neither during the drain, whose advises would be evicted long before the entries
+    /// are consumed, nor for the returned iterators, which are plain vectors. Consumers that read contents have to
+    /// advise them themselves.
+    pub fn into_per_device(mut self) -> (Vec<(u64, std::vec::IntoIter<Entry<T>>)>, Vec<WalkError>) {
+        let (order, strategy) = (self.order, self.sort_strategy);
+        self.disable_prefetching();
+        let mut devices : BTreeMap<u64, Vec<Entry<T>>> = BTreeMap::new();
+        let mut errors = vec![];
+
+        for res in self.by_ref() {
+            match res {
+                Ok(e) => devices.entry(e.dev).or_insert(vec![]).push(e),
+                Err(e) => errors.push(e)
+            }
+        }
+
+        let split = devices.into_iter().map(|(dev, mut entries)| {
+            match order {
+                Inode => strategy.sort(&mut entries, |e| e.ino),
+                Content => strategy.sort(&mut entries, |e| e.offset),
+                _ => {}
+            }
+            (dev, entries.into_iter())
+        }).collect();
+
+        (split, errors)
+    }
+
+    /// Neither the built-in advises nor a custom prefetcher, for walks that only compute the order
+    fn disable_prefetching(&mut self) {
+        self.mountpoints = vec![];
+        self.prefetch_devices.clear();
+        self.prefetcher = None;
+    }
+
+    /// Performs the walk including all extent lookups, but without any prefetching, and returns
+    /// the paths in the order they would have been yielded along with their schedule key:
+    /// the physical offset for entries ordered by content, the inode number for entries ordered by inode
+    /// and the position in the output for entries in discovery order, see `Entry::effective_order`.
+    /// Directories are still read, so this is not free, but file contents are never touched.
+    pub fn plan(mut self) -> std::io::Result<Vec<(PathBuf, u64)>> {
+        self.disable_prefetching();
+        let mut plan = vec![];
+        for res in self {
+            let e = res?;
+            let key = e.schedule_key(plan.len() as u64);
+            plan.push((e.path, key));
+        }
+        Ok(plan)
+    }
+
+    /// Runs the filters against a single discovered entry. User filters are only called as far as needed,
+    /// e.g. the descend filter isn't consulted for files or entries the entry filter already won't descend into.
+    fn decide(&self, path: &Path, ft: &FileType) -> Decision {
+        let filter_path : Cow<Path> = if self.case_insensitive { Cow::Owned(fold_case(path)) } else { Cow::Borrowed(path) };
+
+        let entry = match self.entry_filter {
+            Some(ref filter) => filter(&filter_path, ft),
+            None => FilterDecision { yield_entry: true, descend: true }
+        };
+
+        let not_descended = if !ft.is_dir() {
+            Some(SkipReason::NotADirectory)
+        } else if !entry.descend {
+            Some(SkipReason::EntryFilter)
+        } else if !self.descend_filter.as_ref().map(|f| f(&filter_path, ft)).unwrap_or(true) {
+            Some(SkipReason::DescendFilter)
+        } else if !self.mount_filter.as_ref().and_then(|f| self.mount_table.get(path).map(f)).unwrap_or(true) {
+            Some(SkipReason::MountFilter)
+        } else {
+            None
+        };
+
+        let not_yielded = if !entry.yield_entry {
+            Some(SkipReason::EntryFilter)
+        } else if !self.prefilter.as_ref().map(|f| f(&filter_path, ft)).unwrap_or(true) {
+            Some(SkipReason::Prefilter)
+        } else {
+            None
+        };
+
+        Decision {
+            not_yielded: not_yielded,
+            not_descended: not_descended
+        }
+    }
+
+    /// Explains what the walk would do with `path`, e.g. to find out why an expected file doesn't show up.
+    /// Runs the configured filters against it and its parent directories up to the root it's below, and checks
+    /// the depth limit, skip markers and creation cutoff. Paths outside of all roots are judged as if they were a root.
+    /// Doesn't know about entries the walk drops for other reasons, e.g. errors, `set_max_children_per_dir`
+    /// or `UnknownOffsetPolicy::Drop`. Every checked path is looked up, so this costs a few syscalls.
+    pub fn would_include(&self, path: &Path) -> std::io::Result<Decision> {
+        let ft = self.backend.stat(None, path, false)?.ftype;
+        let root = self.roots.iter().filter(|r| path.starts_with(r)).max_by_key(|r| r.components().count());
+        let depth = root.map(|r| path.components().count() - r.components().count()).unwrap_or(0);
+        let marked = |dir: &Path| self.skip_marker.as_ref().map(|m| self.backend.stat(None, &dir.join(m), false).is_ok()).unwrap_or(false);
+
+        let mut decision = if depth == 0 {
+            Decision {
+                not_yielded: if ft.is_dir() && !self.yield_root { Some(SkipReason::Root) } else { None },
+                not_descended: if ft.is_dir() { None } else { Some(SkipReason::NotADirectory) }
+            }
+        } else {
+            self.decide(path, &ft)
+        };
+
+        if let Some(max) = self.max_path_depth {
+            if depth > max {
+                decision.not_yielded = decision.not_yielded.or(Some(SkipReason::Depth));
+            }
+            if depth >= max {
+                decision.not_descended = decision.not_descended.or(Some(SkipReason::Depth));
+            }
+        }
+
+        if ft.is_dir() && marked(path) {
+            decision.not_descended = decision.not_descended.or(Some(SkipReason::SkipMarker));
+        }
+
+        for (level, dir) in path.ancestors().skip(1).take(depth).enumerate() {
+            if marked(dir) {
+                decision.not_yielded = decision.not_yielded.or(Some(SkipReason::SkipMarker));
+            }
+            // the root itself isn't filtered
+            if level + 1 == depth {
+                break;
+            }
+            let dir_ft = self.backend.stat(None, dir, false)?.ftype;
+            if self.decide(dir, &dir_ft).not_descended.is_some() {
+                decision.not_yielded = decision.not_yielded.or(Some(SkipReason::Ancestor));
+            }
+        }
+
+        let timed = match self.order {
+            _ if self.sweeps_files() => !ft.is_dir(),
+            Inode | Content | RotationalOptimal => true,
+            DirsThenFiles => !ft.is_dir(),
+            _ => false
+        };
+        if let (Some(cutoff), true) = (self.created_after, timed) {
+            let btime = dir::statx_at(None, path, libc::STATX_BTIME).ok()
+                .filter(|stx| stx.stx_mask & libc::STATX_BTIME != 0)
+                .map(|stx| statx_time(stx.stx_btime));
+            if btime.map(|t| t < cutoff).unwrap_or(false) {
+                decision.not_yielded = decision.not_yielded.or(Some(SkipReason::CreatedBefore));
+            }
+        }
+
+        Ok(decision)
+    }
+
+    /// Performs the walk like `plan` and writes the result to a file for other tools to consume, one entry at a time
+    /// so the order doesn't have to fit in memory. Each entry is written with its inode, its path and its schedule key
+    /// as `offset`, which like in `plan` is only a physical offset for entries ordered by content.
+    /// Stops at the first error, leaving a partial manifest.
+    pub fn write_manifest(mut self, path: &Path, format: ManifestFormat) -> std::io::Result<()> {
+        use std::io::Write;
+
+        self.disable_prefetching();
+        let mut w = std::io::BufWriter::new(File::create(path)?);
+
+        if format == ManifestFormat::Json {
+            w.write_all(b"[")?;
+        }
+        for (position, res) in self.enumerate() {
+            let e = res?;
+            let key = e.schedule_key(position as u64);
+            match format {
+                ManifestFormat::Tsv => {
+                    write!(w, "{}\t{}\t", key, e.ino)?;
+                    for &b in e.path.as_os_str().as_bytes() {
+                        match b {
+                            b'\\' => w.write_all(b"\\\\")?,
+                            b'\t' => w.write_all(b"\\t")?,
+                            b'\n' => w.write_all(b"\\n")?,
+                            b => w.write_all(&[b])?
+                        }
+                    }
+                    w.write_all(b"\n")?;
+                }
+                ManifestFormat::Json => {
+                    let p = e.path.to_str().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData,
+                        format!("{} is not valid UTF-8, use ManifestFormat::Tsv", e.path.display())))?;
+                    if position > 0 {
+                        w.write_all(b",")?;
+                    }
+                    write!(w, "\n{{\"offset\":{},\"ino\":{},\"path\":\"", key, e.ino)?;
+                    for c in p.chars() {
+                        match c {
+                            '"' => w.write_all(b"\\\"")?,
+                            '\\' => w.write_all(b"\\\\")?,
+                            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+                            c => write!(w, "{}", c)?
+                        }
+                    }
+                    w.write_all(b"\"}")?;
+                }
+            }
+        }
+        if format == ManifestFormat::Json {
+            w.write_all(b"\n]\n")?;
+        }
+        w.flush()
     }
 
-    pub fn set_prefilter(&mut self, filter: Box<Fn(&Path, &FileType) -> bool>) {
-        self.prefilter = Some(filter)
-    }
+    /// Walks for at most `duration` and returns what was yielded until then, running out of time is not an error.
+    /// If a content batch was being handed out at that point, the rest of it is appended in order
+    /// since its offsets are already known, so the result doesn't end on a cut-off batch. Files collected for
+    /// a batch that hadn't been ordered yet are left out. The deadline is only checked between entries,
+    /// so it can be overrun by a slow directory read or by the extent lookups of a whole batch, see `set_batchsize`.
+    /// Stops at the first error.
+    pub fn scan_for(mut self, duration: Duration) -> std::io::Result<Vec<Entry<T>>> {
+        let deadline = Instant::now() + duration;
+        let mut entries = vec![];
+
+        while Instant::now() < deadline {
+            match self.next() {
+                Some(res) => entries.push(res?),
+                None => return Ok(entries)
+            }
+        }
 
-    pub fn set_batchsize(&mut self, batch: usize) {
-        self.batch_size = batch;
-    }
+        // runs written during the directory walk belong to a batch that hasn't been ordered yet
+        let mask = self.fetch_mask();
+        if let (Some(ref mut spill), Phase::ContentPass) = (self.spill.as_mut(), self.phase) {
+            spill.load_all(&mut self.phy_sorted_leaves, mask)?;
+        }
 
-    fn is_empty(&self) -> bool {
-        self.phy_sorted.is_empty() && self.unordered.is_empty() && self.current_dir.is_none()
-    }
+        // heap order is reversed, so the sorted vec starts with the highest offset
+        let mut rest = std::mem::replace(&mut self.phy_sorted_leaves, BinaryHeap::new()).into_sorted_vec();
+        rest.reverse();
+        let mut deferred = std::mem::replace(&mut self.deferred_leaves, vec![]);
+        deferred.sort_by_key(|l| l.key());
+        entries.extend(rest.into_iter().chain(deferred).map(|Leaf(key, e)| e.at_offset(key)));
 
-    pub fn add_root(&mut self, path : PathBuf) -> std::io::Result<()> {
-        let meta = std::fs::metadata(&path)?;
-        self.add(Entry{path: path, ino: meta.ino(), ftype: meta.file_type(), extents: vec![]}, None);
-        Ok(())
+        Ok(entries)
     }
 
-    fn get_next(&mut self) -> Option<Entry> {
+    fn get_next(&mut self) -> Option<Entry<T>> {
+        let prefetch_started = Instant::now();
         self.prefetch();
+        self.stats.prefetch_time += prefetch_started.elapsed();
 
         if !self.unordered.is_empty() {
             let res = self.unordered.pop_front();
@@ -172,6 +2193,20 @@ impl ToScan {
             return res;
         }
 
+        // without extents inode order is the next best guess for where directory contents are
+        let lowest_inode = self.inode_sorted.keys().next().cloned();
+        if let Some(k) = lowest_inode {
+            let res = self.inode_sorted.remove(&k);
+            self.remove_prefetch(&res);
+            return res;
+        }
+
+        if self.order == Readiness {
+            if let Some(e) = self.next_ready() {
+                return Some(e);
+            }
+        }
+
         let next_key = self.phy_sorted.range((Included(&self.cursor), Included(&std::u64::MAX))).next().map(|(k,_)| *k);
         if let Some(k) = next_key {
             self.cursor = k;
@@ -183,127 +2218,389 @@ impl ToScan {
         None
     }
 
-    fn remove_prefetch(&mut self, e : &Option<Entry>) {
+    /// Takes the queued entry that was advised longest ago, leaving the cursor where it is
+    fn next_ready(&mut self) -> Option<Entry<T>> {
+        let oldest = {
+            let phy_sorted = &self.phy_sorted;
+            self.prefetched.iter()
+                .filter(|&(path, p)| p.pos.and_then(|k| phy_sorted.get(&k)).map(|e| e.path() == path.as_path()).unwrap_or(false))
+                .min_by_key(|&(_, p)| p.advised)
+                .and_then(|(_, p)| p.pos)
+        };
+
+        let res = oldest.and_then(|k| self.phy_sorted.remove(&k).map(|e| e.with_order(Readiness).at_offset(k)));
+        self.remove_prefetch(&res);
+        res
+    }
+
+    fn rewind(&mut self) {
+        if let Some(ref mut cb) = self.seek_callback {
+            if let Some(&to) = self.phy_sorted.keys().next() {
+                cb(self.cursor, to);
+            }
+        }
+        self.cursor = 0;
+        self.last_key = None;
+    }
+
+    /// Everything still in `phy_sorted` lies behind the cursor at this point
+    fn give_up_order_behind_cursor(&mut self) {
+        let behind = std::mem::replace(&mut self.phy_sorted, BTreeMap::new());
+        self.unordered.extend(behind.into_values());
+    }
+
+    fn next_leaf(&mut self) -> Leaf<T> {
+        if let (Some(gap), None) = (self.max_seek_gap, self.combined_key) {
+            while self.phy_sorted_leaves.peek().map(|l| (l.1.sweep(), l.0.saturating_add(gap)) < (self.content_sweep, self.content_pos)).unwrap_or(false) {
+                let far = self.phy_sorted_leaves.pop().unwrap();
+                self.deferred_leaves.push(far);
+                self.refill_leaves();
+            }
+        }
+
+        if self.phy_sorted_leaves.is_empty() {
+            // pick up what was skipped, this is the one big seek back
+            self.phy_sorted_leaves.extend(self.deferred_leaves.drain(..));
+            self.content_pos = 0;
+            self.content_sweep = (0, 0);
+            self.last_key = None;
+        }
+
+        let leaf = match self.geometry {
+            Some(geometry) if self.order == RotationalOptimal => self.pop_shortest_access(geometry),
+            _ => self.phy_sorted_leaves.pop().unwrap()
+        };
+        if leaf.1.sweep() != self.content_sweep {
+            // a new size bucket or device starts with a fresh sweep
+            self.content_sweep = leaf.1.sweep();
+            self.last_key = None;
+        }
+        self.content_pos = leaf.0;
+        leaf
+    }
+
+    /// Replaces the offsets of the leaves that have one with the weighted key of `set_combined_key`
+    fn blend_keys(&mut self, (inode_weight, offset_weight): (f64, f64)) {
+        let leaves = std::mem::replace(&mut self.phy_sorted_leaves, BinaryHeap::new()).into_vec();
+        let located = |l: &&Leaf<T>| l.1.effective_order() == Content;
+        let max_ino = leaves.iter().filter(located).map(|l| l.1.ino).max().unwrap_or(0);
+        let max_offset = leaves.iter().filter(located).map(|l| l.0).max().unwrap_or(0);
+        let normalize = |v: u64, max: u64| if max == 0 { 0.0 } else { v as f64 / max as f64 };
+        // keep clear of the keys used by the unknown offset policy and of float precision limits
+        const SCALE : f64 = (1u64 << 52) as f64;
+
+        self.phy_sorted_leaves = leaves.into_iter().map(|Leaf(offset, e)| {
+            if e.effective_order() != Content {
+                return Leaf(offset, e);
+            }
+            let blended = (inode_weight * normalize(e.ino, max_ino) + offset_weight * normalize(offset, max_offset)) / (inode_weight + offset_weight);
+            Leaf(1 + (blended * SCALE) as u64, e)
+        }).collect();
+    }
+
+    /// Greedy shortest access time first among the next few leaves ahead of the last one.
+    /// Leaves that were passed over end up slightly behind and are picked up with a short seek back.
+    fn pop_shortest_access(&mut self, geometry: Geometry) -> Leaf<T> {
+        const WINDOW : usize = 32;
+
+        let from = self.content_pos;
+        let mut candidates : Vec<Leaf<T>> = (0..WINDOW).filter_map(|_| self.phy_sorted_leaves.pop()).collect();
+        let best = candidates.iter().enumerate()
+            .min_by_key(|&(_, l)| geometry.access_time(from, l.0))
+            .map(|(i, _)| i)
+            .unwrap();
+        let Leaf(offset, e) = candidates.swap_remove(best);
+        self.phy_sorted_leaves.extend(candidates);
+        // files without extents keep their placement by the unknown offset policy
+        let order = if e.effective_order() == Content { RotationalOptimal } else { e.effective_order() };
+        Leaf(offset, e.with_order(order))
+    }
+
+    fn verify_order(&mut self, key: u64, e: &Entry<T>) {
+        // trades ascending order for lower access times on purpose
+        if !self.verify_ordering || e.effective_order() == RotationalOptimal {
+            return;
+        }
+        if let Some(prev) = self.last_key {
+            if key < prev {
+                panic!("ordering violated: {} yielded at {} after an entry at {} ({:?} order)", e.path().display(), key, prev, e.effective_order());
+            }
+        }
+        self.last_key = Some(key);
+    }
+
+    fn remove_prefetch(&mut self, e : &Option<Entry<T>>) {
+        if self.prefetcher.is_some() {
+            return;
+        }
         if let &Some(ref e) = e {
-            if let Some(_) = self.prefetched.remove(e.path()) {
-                self.prefetch_cap = std::cmp::min(2048,self.prefetch_cap * 2 + 1);
+            // never advised, so neither a hit nor a miss
+            if !self.prefetch_dir_blocks && e.file_type().is_dir() {
+                return;
+            }
+            if !self.prefetch_filter.as_ref().map(|f| f(e)).unwrap_or(true) {
+                return;
+            }
+            if let Some(p) = self.prefetched.remove(e.path()) {
+                self.stats.consumed_bytes += p.size;
+                self.adapt_prefetch_cap(true);
+                if let Some(prev) = self.reading.take() {
+                    self.release(prev);
+                }
+                self.reading = Some(p);
             } else {
-                self.prefetch_cap = 2;
-                self.prefetched.clear();
+                self.adapt_prefetch_cap(false);
+                let evicted : Vec<_> = self.prefetched.drain().map(|(_, p)| p).collect();
+                for p in evicted {
+                    self.release(p);
+                }
             }
 
         }
     }
 
+    fn adapt_prefetch_cap(&mut self, hit: bool) {
+        let (min, max) = self.prefetch_cap_bounds;
+        self.prefetch_cap = std::cmp::max(min, std::cmp::min(max, self.prefetch_cap_policy.next(self.prefetch_cap, hit)));
+    }
+
+    /// Drops an advised entry from the page cache again if the cache pressure is limited
+    fn release(&mut self, p: Prefetched) {
+        if self.max_cache_pressure.is_none() {
+            return;
+        }
+        let mut devices: HashMap<PathBuf, Option<File>> = HashMap::new();
+        for (device, offset, len) in p.ranges {
+            let f = devices.entry(device.clone()).or_insert_with(|| File::open(&device).ok());
+            if let Some(ref f) = *f {
+                fadvise_dontneed(f, offset, len);
+            }
+            self.resident_bytes = self.resident_bytes.saturating_sub(len);
+        }
+    }
+
     fn prefetch(&mut self) {
-        if self.mountpoints.is_empty() {
+        if self.prefetcher.is_some() && self.order != ReaddirNatural {
+            let (max, dir_blocks) = (self.prefetch_cap_bounds.1, self.prefetch_dir_blocks);
+            let upcoming : Vec<&Entry<T>> = self.unordered.iter().chain(self.inode_sorted.values())
+                .chain(self.phy_sorted.range((Included(&self.cursor), Included(&std::u64::MAX))).map(|(_, v)| v))
+                .chain(self.phy_sorted.range((Included(&0), Excluded(&self.cursor))).map(|(_, v)| v))
+                .filter(|e| dir_blocks || !e.file_type().is_dir())
+                .filter(|e| self.prefetch_filter.as_ref().map(|f| f(e)).unwrap_or(true))
+                .take(max)
+                .collect();
+            if let Some(ref mut prefetcher) = self.prefetcher {
+                prefetcher(&upcoming);
+            }
+            return;
+        }
+
+        if self.prefetch_devices.is_empty() || self.order == ReaddirNatural {
             return;
         }
 
         const LIMIT : u64 = 8*1024*1024;
 
-        let consumed = self.prefetched.iter().map(|ref tuple| tuple.1).sum::<u64>();
+        if let Some(distance) = self.prefetch_expiry {
+            let cursor = self.cursor;
+            let expired : Vec<PathBuf> = self.prefetched.iter()
+                .filter(|&(_, p)| p.pos.map(|pos| pos.saturating_add(distance) < cursor).unwrap_or(false))
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in expired {
+                if let Some(p) = self.prefetched.remove(&path) {
+                    self.release(p);
+                }
+            }
+        }
+
+        let consumed = self.prefetched.values().map(|p| p.size).sum::<u64>();
         let mut remaining = LIMIT.saturating_sub(consumed);
         let prev_fetched = self.prefetched.len();
 
         // hysteresis
         if remaining < LIMIT/2 {
+            self.stats.prefetch_window_full += 1;
             return;
         }
 
-        let unordered_iter = self.unordered.iter();
-        let ordered_iter_front = self.phy_sorted.range((Included(&self.cursor), Included(&std::u64::MAX))).map(|(_,v)| v);
-        let ordered_iter_tail = self.phy_sorted.range((Included(&0), Excluded(&self.cursor))).map(|(_,v)| v);
+        if let Some(max) = self.max_cache_pressure {
+            remaining = std::cmp::min(remaining, max.saturating_sub(self.resident_bytes));
+        }
+
+        if let Some(max) = self.max_bytes {
+            remaining = std::cmp::min(remaining, max.saturating_sub(self.yielded_bytes));
+        }
+
+        let unordered_iter = self.unordered.iter().chain(self.inode_sorted.values()).map(|v| (None, v));
+        let ordered_iter_front = self.phy_sorted.range((Included(&self.cursor), Included(&std::u64::MAX))).map(|(k,v)| (Some(*k), v));
+        let ordered_iter_tail = self.phy_sorted.range((Included(&0), Excluded(&self.cursor))).map(|(k,v)| (Some(*k), v));
+        let now = Instant::now();
 
         let mut prune = vec![];
+        let mut ranges = vec![];
+        let mut window_filled = false;
+        let min_fadvise = self.min_fadvise_bytes;
+        let max_chunk = self.max_fadvise_chunk;
 
         {
             let mut device_groups = HashMap::new();
 
-            for e in unordered_iter.chain(ordered_iter_front).chain(ordered_iter_tail) {
-                if remaining == 0 {
+            for (pos, e) in unordered_iter.chain(ordered_iter_front).chain(ordered_iter_tail) {
+                if remaining == 0 || self.prefetched.len() > self.prefetch_cap + 1 {
+                    window_filled = true;
                     break;
                 }
 
-                if self.prefetched.len() > self.prefetch_cap + 1 {
-                    break;
+                if self.prefetched.contains_key(e.path()) || (!self.prefetch_dir_blocks && e.file_type().is_dir()) {
+                    continue;
                 }
 
-                if self.prefetched.contains_key(e.path()) {
-                    continue;
+                if let Some(ref filter) = self.prefetch_filter {
+                    if !filter(e) {
+                        continue;
+                    }
                 }
 
                 let size = e.extent_sum();
                 remaining = remaining.saturating_sub(size);
-                self.prefetched.insert(e.path().to_owned(), size);
+                let mut prefetched = Prefetched { size: size, advised: now, pos: pos, ranges: vec![] };
 
                 let mount = self.mountpoints.iter().rev().find(|mnt| e.path().starts_with(&mnt.file));
 
                 // TODO: only try to open devices once
                 match mount {
                     Some(&mnt::MountEntry {ref spec, ref vfstype, ..})
-                    if vfstype == "ext4" || vfstype == "ext3"
+                    if (self.device_map.is_some() || device_prefetch_supported(vfstype)) && self.prefetch_devices.contains(spec)
                     => {
-                        let mount_slot = device_groups.entry(spec).or_insert(vec![]);
-                        mount_slot.extend(&e.extents);
+                        for ext in &e.extents {
+                            let (device, offset) = match self.device_map {
+                                Some(ref map) => map(ext.physical),
+                                None => (PathBuf::from(spec), ext.physical)
+                            };
+                            device_groups.entry(device.clone()).or_insert(vec![]).push((offset, ext.length));
+                            prefetched.ranges.push((device, offset, ext.length));
+                        }
+                        self.resident_bytes += size;
                     }
                     _ => {}
                 }
+
+                self.prefetched.insert(e.path().to_owned(), prefetched);
             }
 
-            for (p, extents) in device_groups {
-                let mut ordered_extents = extents.to_vec();
-                ordered_extents.sort_by_key(|e| e.physical);
+            for (p, mut ordered_extents) in device_groups {
+                ordered_extents.sort();
 
-                if let Ok(f) = File::open(p) {
+                if let Ok(f) = File::open(&p) {
+                    let readahead = *self.readahead_sizes.entry(p.clone()).or_insert_with(|| readahead_bytes(&f));
 
                     let mut i = 0;
+                    let mut pending : Option<(u64, u64)> = None;
 
                     while i < ordered_extents.len() {
-                        let ext1 = ordered_extents[i];
-                        let offset = ext1.physical;
-                        let mut end = offset + ext1.length;
+                        let (offset, len) = ordered_extents[i];
+                        let mut end = offset + len;
 
                         for j in i+1..ordered_extents.len() {
-                            let ref ext2 = ordered_extents[j];
-                            if ext2.physical > end {
+                            let (offset2, len2) = ordered_extents[j];
+                            if offset2 > end {
                                 break;
                             }
 
                             i = j;
 
-                            end = ext2.physical+ext2.length;
+                            end = offset2 + len2;
                         }
 
                         i+=1;
 
-                        unsafe {
-                            libc::posix_fadvise(f.as_raw_fd(), offset as i64, (end - offset) as i64, libc::POSIX_FADV_WILLNEED);
-                        }
+                        // accumulate small runs separated by small gaps into a single advise
+                        pending = match pending {
+                            Some((start, pending_end)) if pending_end - start < min_fadvise && offset - pending_end < min_fadvise => {
+                                Some((start, std::cmp::max(end, pending_end)))
+                            }
+                            Some((start, pending_end)) => {
+                                let (start, pending_end) = align_to_readahead(start, pending_end, readahead);
+                                fadvise_willneed(&f, start, pending_end, max_chunk);
+                                ranges.push((p.clone(), start, pending_end - start));
+                                Some((offset, end))
+                            }
+                            None => Some((offset, end))
+                        };
+                    }
+
+                    if let Some((start, end)) = pending {
+                        let (start, end) = align_to_readahead(start, end, readahead);
+                        fadvise_willneed(&f, start, end, max_chunk);
+                        ranges.push((p.clone(), start, end - start));
                     }
                 } else {
-                    prune.push(p.to_owned());
+                    prune.push(p);
                 }
             }
 
         }
 
+        if let Some(ref mut trace) = self.trace {
+            for &(ref device, offset, len) in &ranges {
+                trace.push(TraceEvent::Advise { device: device.clone(), offset: offset, len: len });
+            }
+        }
+
+        self.stats.prefetched_bytes += ranges.iter().map(|&(_, _, len)| len).sum::<u64>();
+        self.last_prefetch_ranges = ranges;
+
+        if !window_filled {
+            self.stats.prefetch_window_starved += 1;
+        }
+
         //println!("bytes: {} -> {}, f: {}->{}, sc: {}", LIMIT-consumed, remaining, prev_fetched ,self.prefetched.len(), self.prefetch_cap);
 
-        if prune.len() > 0 {
-            self.mountpoints.retain(|e| prune.contains(&e.spec));
+        // with a device map this may name a device that is not a mount spec, in which case nothing is pruned
+        for spec in prune {
+            if let Some(spec) = spec.to_str() {
+                self.prefetch_devices.remove(spec);
+            }
         }
 
 
     }
 
-    pub fn add(&mut self, to_add : Entry, pos : Option<u64>) {
+    fn schedule(&mut self, to_add: Entry<T>) {
+        if self.order == ReaddirNatural {
+            self.add(to_add, None);
+        } else if self.dir_sort_key == DirKey::Inode && to_add.file_type().is_dir() && !self.sweeps_files() {
+            let ino = to_add.ino;
+            self.add(to_add, Some(ino));
+        } else if let Some(offset) = lowest_physical(&to_add.extents) {
+            self.add(to_add, Some(offset));
+        } else {
+            // TODO: fall back to inode-order? depth-first?
+            // skip adding non-directories in content order?
+            self.add(to_add, None);
+        }
+    }
+
+    /// Queues an entry whose extents are already known, e.g. from a cache of earlier FIEMAP results,
+    /// at the offset of its lowest physical extent. Entries without extents are queued unordered,
+    /// or by inode in `Order::Inode` and `Order::Content`.
+    pub fn add_prescheduled(&mut self, entry: Entry<T>) {
+        self.schedule(entry);
+    }
+
+    pub fn add(&mut self, to_add : Entry<T>, pos : Option<u64>) {
         match pos {
             Some(idx) => {
                 if let Some(old) = self.phy_sorted.insert(idx, to_add) {
                     self.unordered.push_back(old);
                 }
             }
+            None if self.order == Inode || self.order == Content || self.order == RotationalOptimal => {
+                if let Some(old) = self.inode_sorted.insert((to_add.dev, to_add.ino), to_add) {
+                    self.unordered.push_back(old);
+                }
+            }
             None => {
                 self.unordered.push_back(to_add);
             }
@@ -313,139 +2610,559 @@ impl ToScan {
 
 }
 
-impl Iterator for ToScan {
-    type Item = std::io::Result<Entry>;
+/// FIEMAP for a file that is about to be ordered by content, counting lookups that come back empty for no good reason
+fn file_extents(backend: &Backend, base: Option<RawFd>, path: &Path, dev: u64, stats: &mut Stats, analysis: &mut ExtentAnalysis) -> Vec<FileExtent> {
+    let extents = backend.extent_map(base, path).unwrap_or(vec![]);
+    stats.files_mapped += 1;
+    if extents.is_empty() && backend.stat(base, path, false).map(|st| st.ftype.is_file() && st.len > 0).unwrap_or(false) {
+        stats.files_without_extents += 1;
+    }
+    if let Some(ref mut fragmentation) = analysis.fragmentation {
+        fragmentation.record(path, &extents);
+    }
+    if let Some(ref mut shared) = analysis.shared {
+        shared.record(path, dev, &extents);
+    }
+    extents
+}
 
-    fn next(&mut self) -> Option<std::io::Result<Entry>> {
+/// Optional bookkeeping on the extents looked up during the walk
+#[derive(Default)]
+struct ExtentAnalysis {
+    fragmentation: Option<Fragmentation>,
+    shared: Option<SharedExtents>,
+}
 
-        while self.phase == Phase::DirWalk && !self.is_empty() {
-            if self.current_dir.is_none() {
-                let nxt = match self.get_next() {
-                    Some(e) => e,
-                    None => {
-                        self.cursor = 0;
-                        continue;
-                    }
-                };
+/// The `top_n` entries with the most fragments seen so far, most fragmented first
+struct Fragmentation {
+    top_n: usize,
+    worst: Vec<(PathBuf, usize)>,
+}
 
-                match read_dir(nxt.path()) {
-                    Ok(dir_iter) => {
-                        self.current_dir = Some(dir_iter);
-                    },
-                    Err(open_err) => return Some(Err(open_err))
-                }
+impl Fragmentation {
+    fn record(&mut self, path: &Path, extents: &[FileExtent]) {
+        // FIEMAP splits long contiguous runs into several extents, those don't count as fragments
+        let fragments = extents.iter().enumerate()
+            .filter(|&(i, e)| i == 0 || extents[i - 1].physical + extents[i - 1].length != e.physical)
+            .count();
+        if fragments < 2 || self.top_n == 0 {
+            return;
+        }
+        if self.worst.len() == self.top_n && self.worst.last().map(|&(_, f)| f >= fragments).unwrap_or(false) {
+            return;
+        }
+        let idx = self.worst.iter().position(|&(_, f)| f < fragments).unwrap_or(self.worst.len());
+        self.worst.insert(idx, (path.to_owned(), fragments));
+        self.worst.truncate(self.top_n);
+    }
+}
+
+#[derive(Default)]
+struct SharedExtents {
+    paths: Vec<PathBuf>,
+    /// `(dev, start, end, index into paths)`
+    ranges: Vec<(u64, u64, u64, usize)>,
+}
+
+impl SharedExtents {
+    fn record(&mut self, path: &Path, dev: u64, extents: &[FileExtent]) {
+        if extents.is_empty() {
+            return;
+        }
+        let idx = self.paths.len();
+        self.paths.push(path.to_owned());
+        self.ranges.extend(extents.iter().map(|e| (dev, e.physical, e.physical + e.length, idx)));
+    }
+
+    fn groups(&self) -> Vec<Vec<PathBuf>> {
+        // union-find over paths, joined whenever their ranges overlap in a sweep over the sorted ranges
+        fn find(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
             }
+            i
+        }
+
+        let mut parent : Vec<usize> = (0..self.paths.len()).collect();
+        let mut sorted = self.ranges.clone();
+        sorted.sort();
+
+        let mut current : Option<(u64, u64, usize)> = None;
+        for &(dev, start, end, idx) in &sorted {
+            current = match current {
+                Some((cur_dev, cur_end, cur_idx)) if cur_dev == dev && start < cur_end => {
+                    let (a, b) = (find(&mut parent, cur_idx), find(&mut parent, idx));
+                    parent[a] = b;
+                    Some((dev, std::cmp::max(cur_end, end), idx))
+                }
+                _ => Some((dev, end, idx))
+            };
+        }
+
+        let mut groups : BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+        for i in 0..self.paths.len() {
+            let root = find(&mut parent, i);
+            groups.entry(root).or_insert(vec![]).push(self.paths[i].clone());
+        }
+        groups.into_values().filter(|g| g.len() > 1).collect()
+    }
+}
+
+/// Filesystems living on the single block device named by the mount spec, so that FIEMAP's
+/// physical offsets can be advised directly against that device.
+/// Excludes multi-device filesystems like btrfs where physical offsets are in a logical address space.
+fn device_prefetch_supported(vfstype: &str) -> bool {
+    match vfstype {
+        "ext3" | "ext4" | "f2fs" => true,
+        _ => false
+    }
+}
+
+/// Extent maps are in logical order, which says nothing about where on disk a fragmented file starts
+fn lowest_physical(extents: &[FileExtent]) -> Option<u64> {
+    extents.iter().map(|e| e.physical).min()
+}
+
+/// Unicode lowercasing where the path is valid UTF-8, ASCII-only otherwise
+fn fold_case(p: &Path) -> PathBuf {
+    match p.to_str() {
+        Some(s) => PathBuf::from(s.to_lowercase()),
+        None => PathBuf::from(OsStr::from_bytes(&p.as_os_str().as_bytes().to_ascii_lowercase()))
+    }
+}
+
+/// _IOR(0x12, 114, size_t), not provided by libc
+const BLKGETSIZE64: libc::c_ulong = 0x80081272;
 
-            let mut entry = None;
+/// _IO(0x12, 99), not provided by libc. The same value as `queue/read_ahead_kb` in sysfs, but in 512 byte sectors
+const BLKRAGET: libc::c_ulong = 0x1263;
+
+fn readahead_bytes(dev: &File) -> Option<u64> {
+    let mut sectors: libc::c_long = 0;
+    let ret = unsafe { libc::ioctl(dev.as_raw_fd(), BLKRAGET, &mut sectors) };
+    if ret != 0 || sectors <= 0 {
+        return None;
+    }
+    Some(sectors as u64 * 512)
+}
+
+/// Rounds a range out to multiples of the readahead window so that each advise covers whole windows
+fn align_to_readahead(start: u64, end: u64, readahead: Option<u64>) -> (u64, u64) {
+    match readahead {
+        Some(ra) => (start / ra * ra, end.saturating_add(ra - 1) / ra * ra),
+        None => (start, end)
+    }
+}
+
+fn block_device_size(path: &Path) -> Option<u64> {
+    let mount = mnt::get_mount(path).ok()??;
+    let dev = File::open(&mount.spec).ok()?;
+    let mut size: u64 = 0;
+    let ret = unsafe { libc::ioctl(dev.as_raw_fd(), BLKGETSIZE64, &mut size) };
+    if ret != 0 || size == 0 {
+        return None;
+    }
+    Some(size)
+}
 
-            if let Some(ref mut iter) = self.current_dir {
-                entry = iter.next();
+fn retry<R, F: FnMut() -> std::io::Result<R>>(policy: Option<(u32, Duration)>, mut f: F) -> std::io::Result<R> {
+    let (attempts, mut backoff) = policy.unwrap_or((1, Duration::from_millis(0)));
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(ref e) if attempt < attempts && is_transient(e) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
             }
+            r => return r
+        }
+    }
+}
 
-            match entry {
-                None => {
-                    self.current_dir = None;
-                    continue;
+fn is_transient(e: &std::io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::EIO) | Some(libc::EAGAIN) | Some(libc::ETIMEDOUT) => true,
+        _ => false
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpus: &[usize]) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus.iter().filter(|&&cpu| cpu < libc::CPU_SETSIZE as usize) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_: &[usize]) {}
+
+fn fadvise_dontneed(f: &File, offset: u64, len: u64) {
+    unsafe {
+        libc::posix_fadvise(f.as_raw_fd(), offset as i64, len as i64, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+fn fadvise_willneed(f: &File, start: u64, end: u64, max_chunk: u64) {
+    let mut offset = start;
+    // issue chunks in ascending order so the earliest-needed data gets queued first
+    while offset < end {
+        let len = if max_chunk == 0 { end - offset } else { std::cmp::min(max_chunk, end - offset) };
+        unsafe {
+            libc::posix_fadvise(f.as_raw_fd(), offset as i64, len as i64, libc::POSIX_FADV_WILLNEED);
+        }
+        offset += len;
+    }
+}
+
+impl<T: Clone> Iterator for ToScan<T> {
+    type Item = Result<Entry<T>, WalkError>;
+
+    fn next(&mut self) -> Option<Result<Entry<T>, WalkError>> {
+        let mut next = self.next_entry();
+        if let (Some(max), Some(&Ok(ref e))) = (self.max_bytes, next.as_ref()) {
+            self.yielded_bytes += e.data_bytes();
+            if self.yielded_bytes > max {
+                // drops whatever was prefetched for the entries that won't be reached
+                self.finish();
+                return None;
+            }
+        }
+        if let (Some(trace), Some(&Ok(ref e))) = (self.trace.as_mut(), next.as_ref()) {
+            let key = e.schedule_key(trace.yielded());
+            trace.push(TraceEvent::Yield { path: e.path.clone(), key: key });
+        }
+        if let (Some(visited), Some(&Ok(ref e))) = (self.visited.as_mut(), next.as_ref()) {
+            visited.push((e.dev, e.ino, e.path.clone()));
+        }
+        if let (true, Some(&mut Ok(ref mut e))) = (self.measure_residency, next.as_mut()) {
+            let content = match e.effective_order() {
+                Content | RotationalOptimal | Readiness => true,
+                _ => false
+            };
+            if content && e.file_type().is_file() {
+                if let Ok((resident, total)) = dir::resident_pages(e.base_fd(), &e.path, self.noatime) {
+                    self.stats.pages_resident += resident;
+                    self.stats.pages_checked += total;
+                    if total > 0 {
+                        e.cached = Some(resident == total);
+                    }
                 }
-                Some(Err(e)) => return Some(Err(e)),
-                Some(Ok(dent)) => {
-                    let meta = match dent.file_type() {
-                        Ok(ft) => ft,
-                        Err(e) => return Some(Err(e))
-                    };
+            }
+        }
+        next
+    }
+}
+
+impl<T: Clone> ToScan<T> {
+    fn next_entry(&mut self) -> Option<Result<Entry<T>, WalkError>> {
+        if let Some(cpus) = self.cpu_affinity.take() {
+            pin_current_thread(&cpus);
+        }
 
-                    // TODO: Better phase-switching?
-                    // move to inode pass? won't start the next dir before this one is done anyway
-                    if meta.is_dir() {
+        if self.phase_started.is_none() {
+            self.phase_started = Some(Instant::now());
+        }
 
-                        let extents = match get_file_extent_map_for_path(dent.path()) {
-                            Ok(extents) => extents,
-                            _ => vec![]
-                        };
+        // only goes around again when a content batch ended up empty
+        loop {
+            while self.phase == Phase::DirWalk && !self.is_empty() {
+                self.report_progress();
 
-                        let to_add = Entry::new(dent.path(), meta, dent.ino(), extents);
+                if let Some(distance) = self.pipeline_distance {
+                    if self.phy_sorted_leaves.peek().map(|l| l.0.saturating_add(distance) <= self.cursor).unwrap_or(false) {
+                        let Leaf(key, dent) = self.phy_sorted_leaves.pop().unwrap();
+                        self.content_pos = key;
+                        return Some(self.revalidate(dent.at_offset(key)));
+                    }
+                }
 
-                        if { !to_add.extents.is_empty() } {
-                            let offset = to_add.extents[0].physical;
-                            self.add(to_add, Some(offset));
-                        } else {
-                            // TODO: fall back to inode-order? depth-first?
-                            // skip adding non-directories in content order?
-                            self.add(to_add, None);
+                if self.current_dir.is_none() {
+                    let nxt = match self.get_next() {
+                        Some(e) => e,
+                        None => {
+                            match self.cursor_policy {
+                                CursorPolicy::Wrap => self.rewind(),
+                                CursorPolicy::SingleSweep => self.give_up_order_behind_cursor()
+                            }
+                            continue;
                         }
+                    };
 
+                    // files scheduled by single sweep mode, requeue, add_prescheduled or as roots
+                    if !nxt.file_type().is_dir() {
+                        let offset = lowest_physical(&nxt.extents);
+                        let order = match (nxt.effective_order(), offset) {
+                            (Readiness, _) => Readiness,
+                            (_, None) => Dentries,
+                            _ => Content
+                        };
+                        let mut nxt = nxt.with_order(order);
+                        if let (Content, Some(offset)) = (order, offset) {
+                            nxt = nxt.at_offset(offset);
+                            // those that collided with another key come from `unordered` and are out of order anyway
+                            if offset == self.cursor {
+                                self.verify_order(offset, &nxt);
+                            }
+                        }
+                        return Some(Ok(nxt));
+                    }
 
+                    if let Some(max) = self.max_path_depth.filter(|&max| nxt.depth >= max) {
+                        let msg = format!("directory nesting exceeds {} levels, not descended", max);
+                        return Some(Err(WalkError::new(nxt.path, std::io::Error::new(std::io::ErrorKind::Other, msg))));
                     }
 
-                    if let Some(ref filter) = self.prefilter {
-                        if !filter(&dent.path(), &meta) {
+                    if let Some(ref marker) = self.skip_marker {
+                        if self.backend.stat(nxt.base_fd(), &nxt.path.join(marker), false).is_ok() {
+                            self.stats.marked_dirs += 1;
                             continue;
                         }
                     }
 
-                    match self.order {
-                        Order::Dentries => {
-                            return Some(Ok(Entry::new(dent.path(), meta, dent.ino(), vec![])))
+                    let (backend, noatime) = (&self.backend, self.noatime);
+                    match retry(self.retry, || backend.read_dir(nxt.base_fd(), nxt.path(), noatime)) {
+                        Ok(dir_iter) => {
+                            self.devices_seen.insert(dir_iter.dev());
+                            let root = if self.yield_root && nxt.depth == 0 {
+                                let mut root = Entry::with_tag(nxt.path.clone(), nxt.ftype, nxt.ino, vec![], nxt.tag.clone());
+                                root.base = nxt.base.clone();
+                                Some(root)
+                            } else {
+                                None
+                            };
+                            self.current_dir = Some((nxt.path, dir_iter, nxt.tag, nxt.base));
+                            self.current_depth = nxt.depth;
+                            self.children_read = 0;
+                            if let Some(root) = root {
+                                match self.order {
+                                    Dentries | DirsThenFiles | ReaddirNatural => return Some(Ok(root)),
+                                    _ if self.sweeps_files() => return Some(Ok(root)),
+                                    _ => self.inode_ordered.push(root)
+                                }
+                            }
+                        },
+                        Err(ref open_err) if self.skip_replaced_dirs && open_err.raw_os_error() == Some(libc::ENOTDIR) => {
+                            self.stats.replaced_dirs += 1;
+                            continue;
                         }
-                        Order::Inode | Order::Content => {
-                            self.inode_ordered.push(Entry::new(dent.path(), meta, dent.ino(), vec![]));
+                        Err(ref open_err) if self.yield_denied_dirs && open_err.raw_os_error() == Some(libc::EACCES) => {
+                            let mut placeholder = nxt;
+                            placeholder.descend_failed = true;
+                            return Some(Ok(placeholder));
                         }
+                        Err(open_err) => return Some(Err(WalkError::new(nxt.path, open_err)))
                     }
                 }
-            }
-
-            if self.inode_ordered.len() >= self.batch_size {
-                assert!(self.order != Dentries);
-                self.phase = Phase::InodePass;
-                // reverse sort so we can pop
-                self.inode_ordered.sort_by_key(|dent| std::u64::MAX - dent.ino());
-            }
-        }
 
+                let mut entry = None;
 
-        if self.phase == Phase::InodePass || (self.is_empty() && self.inode_ordered.len() > 0)  {
-            assert!(self.inode_ordered.len() > 0);
+                if let Some((ref dir, ref mut iter, ref parent_tag, ref base)) = self.current_dir {
+                    if self.children_read < self.max_children {
+                        entry = iter.next().map(|r| r.map(|dent| (dent, parent_tag.clone(), base.clone())).map_err(|e| WalkError::new(dir.to_owned(), e)));
+                        if let Some(Ok(_)) = entry {
+                            self.children_read += 1;
+                        }
+                    } else if self.children_read == self.max_children && iter.next().is_some() {
+                        // only reported once, the next round closes the directory
+                        self.children_read += 1;
+                        let msg = format!("directory has more than {} entries, skipped the rest", self.max_children);
+                        entry = Some(Err(WalkError::new(dir.to_owned(), std::io::Error::new(std::io::ErrorKind::Other, msg))));
+                    }
+                }
 
-            match self.order {
-                Order::Inode => {
-                    let dent = self.inode_ordered.pop().unwrap();
-                    if self.inode_ordered.len() == 0 {
-                        self.phase = Phase::DirWalk;
+                match entry {
+                    None => {
+                        self.current_dir = None;
+                        if self.content_trigger == Trigger::FirstDirComplete && !self.first_batch_started
+                            && self.inode_ordered.len() > 0 && (self.order != DirsThenFiles || self.streaming) {
+                            self.start_inode_pass();
+                        }
+                        continue;
                     }
-                    return Some(Ok(dent))
-                },
-                Order::Content => {
-                    for e in self.inode_ordered.drain(0..).rev() {
-                        let offset = match get_file_extent_map_for_path(e.path()) {
-                            Ok(ref extents) if !extents.is_empty() => extents[0].physical,
-                            _ => 0
+                    Some(Err(e)) => return Some(Err(e)),
+                    Some(Ok((dent, tag, base))) => {
+                        let file_types = &mut self.file_types;
+                        let meta = match retry(self.retry, || dent.file_type(file_types)) {
+                            Ok(ft) => ft,
+                            Err(e) => return Some(Err(WalkError::new(dent.into_path(), e)))
                         };
-                        self.phy_sorted_leaves.push((offset, e));
+
+                        // TODO: Better phase-switching?
+                        // move to inode pass? won't start the next dir before this one is done anyway
+                        let decision = self.decide(dent.path(), &meta);
+
+                        if decision.not_descended.is_none() {
+
+                            // only used to place the directory in the queue
+                            let extents = if self.order == ReaddirNatural {
+                                vec![]
+                            } else {
+                                match self.backend.extent_map(dent.base(), dent.path()) {
+                                    Ok(extents) => extents,
+                                    _ => vec![]
+                                }
+                            };
+
+                            if decision.not_yielded.is_some() {
+                                self.schedule(Entry::from_dent(dent, self.current_depth + 1, meta, extents, tag, base));
+                                continue;
+                            }
+                            // queued and yielded, both need a path. Only directories pay for this copy,
+                            // about 6% of the entries of a typical tree
+                            self.schedule(Entry::from_dent(dent.clone(), self.current_depth + 1, meta, extents, tag.clone(), base.clone()));
+                        }
+
+                        if decision.not_yielded.is_some() {
+                            continue;
+                        }
+
+                        match self.order {
+                            Order::Dentries | Order::ReaddirNatural => {
+                                return Some(Ok(Entry::from_dent(dent, self.current_depth + 1, meta, vec![], tag, base)))
+                            }
+                            Order::Content | Order::Readiness if self.sweeps_files() => {
+                                if meta.is_dir() {
+                                    return Some(Ok(Entry::from_dent(dent, self.current_depth + 1, meta, vec![], tag, base)))
+                                }
+                                if self.skip_inodes.contains(&(dent.dev(), dent.ino())) {
+                                    continue;
+                                }
+                                let mut e = Entry::from_dent(dent, self.current_depth + 1, meta, vec![], tag, base).fetch_statx(self.fetch_mask());
+                                if e.created_before(self.created_after) {
+                                    continue;
+                                }
+                                e.extents = file_extents(&*self.backend, e.base_fd(), &e.path, e.dev, &mut self.stats, &mut self.analysis);
+                                self.check_extent_coverage();
+                                self.schedule(e);
+                            }
+                            Order::DirsThenFiles if meta.is_dir() => {
+                                return Some(Ok(Entry::from_dent(dent, self.current_depth + 1, meta, vec![], tag, base)))
+                            }
+                            Order::Content if self.pipeline_distance.is_some() => {
+                                if self.skip_inodes.contains(&(dent.dev(), dent.ino())) {
+                                    continue;
+                                }
+                                let extents = file_extents(&*self.backend, dent.base(), dent.path(), dent.dev(), &mut self.stats, &mut self.analysis);
+                                let (offset, sweep, order) = match lowest_physical(&extents) {
+                                    // neither size priority nor device map apply while pipelining
+                                    Some(offset) => (offset, (SizePriority::None.bucket(0), 0), Content),
+                                    None => match self.unknown_offset_policy.placement() {
+                                        Some((offset, bucket, rank)) => (offset, (bucket, rank), Inode),
+                                        None => continue
+                                    }
+                                };
+                                let mut e = Entry::from_dent(dent, self.current_depth + 1, meta, vec![], tag, base).with_order(order).fetch_statx(self.fetch_mask());
+                                e.size_bucket = sweep.0;
+                                e.device_rank = sweep.1;
+                                e.located_bytes = extents.iter().map(|x| x.length).sum();
+                                self.check_extent_coverage();
+                                if e.created_before(self.created_after) {
+                                    continue;
+                                }
+                                self.phy_sorted_leaves.push(Leaf(offset, e));
+                            }
+                            Order::Inode | Order::Content | Order::RotationalOptimal | Order::DirsThenFiles => {
+                                if self.skip_inodes.contains(&(dent.dev(), dent.ino())) {
+                                    continue;
+                                }
+                                self.inode_ordered.push(Entry::from_dent(dent, self.current_depth + 1, meta, vec![], tag, base));
+                            }
+                            Order::Readiness => {panic!("illegal state")}
+                        }
                     }
-                    self.phy_sorted_leaves.sort_by_key(|pair| pair.0);
-                    self.phase = Phase::ContentPass;
-                    assert!(self.phy_sorted_leaves.len() > 0);
-                },
-                _ => {panic!("illegal state")}
-            }
+                }
 
-        }
+                if self.spills() && self.inode_ordered.len() >= self.spill_threshold {
+                    if let Err(e) = self.spill_batch() {
+                        return Some(Err(e));
+                    }
+                }
+
+                let threshold = match self.content_trigger {
+                    Trigger::AfterNFiles(n) if !self.first_batch_started => std::cmp::max(1, n),
+                    _ => self.batch_size
+                };
 
-        if self.phase == Phase::ContentPass || (self.is_empty() && self.phy_sorted_leaves.len() > 0) {
-            assert!(self.phy_sorted_leaves.len() > 0);
-            let dent = self.phy_sorted_leaves.pop().unwrap().1;
-            if self.phy_sorted_leaves.len() == 0 {
-                self.phase = Phase::DirWalk;
+                if self.inode_ordered.len() >= threshold && (self.order != DirsThenFiles || self.streaming) {
+                    assert!(self.order != Dentries);
+                    self.start_inode_pass();
+                }
             }
-            return Some(Ok(dent))
-        }
 
+            // the tree is exhausted, flush the last partial batch
+            if self.phase == Phase::DirWalk && self.is_empty() && (self.inode_ordered.len() > 0 || self.spill_pending() > 0) {
+                self.start_inode_pass();
+            }
 
+            if self.phase == Phase::InodePass {
+                assert!(self.inode_ordered.len() > 0 || self.spill_pending() > 0);
 
+                match self.order {
+                    Order::Inode => {
+                        let dent = self.inode_ordered.pop().unwrap().with_order(Inode).fetch_statx(self.fetch_mask());
+                        if self.inode_ordered.len() == 0 {
+                            self.set_phase(Phase::DirWalk);
+                        }
+                        if dent.created_before(self.created_after) {
+                            continue;
+                        }
+                        let key = match self.inode_direction {
+                            Direction::Ascending => dent.ino(),
+                            Direction::Descending => std::u64::MAX - dent.ino()
+                        };
+                        self.verify_order(key, &dent);
+                        return Some(self.revalidate(dent))
+                    },
+                    Order::Content | Order::RotationalOptimal | Order::DirsThenFiles => {
+                        self.locate_leaves();
+                        if let (Some(weights), true) = (self.combined_key, self.order != RotationalOptimal) {
+                            self.blend_keys(weights);
+                        }
+                        self.check_extent_coverage();
+                        self.refill_leaves();
+                        if self.phy_sorted_leaves.is_empty() {
+                            // every file of the batch was dropped by the unknown offset policy or the creation cutoff
+                            self.set_phase(Phase::DirWalk);
+                            continue;
+                        }
+                        self.set_phase(Phase::ContentPass);
+                    },
+                    _ => {panic!("illegal state")}
+                }
 
+            }
 
+            if self.phase == Phase::ContentPass || (self.is_empty() && self.phy_sorted_leaves.len() > 0) {
+                self.refill_leaves();
+                if let Some(e) = self.spill_error.take() {
+                    if self.phy_sorted_leaves.is_empty() && self.deferred_leaves.is_empty() {
+                        self.set_phase(Phase::DirWalk);
+                    }
+                    return Some(Err(e));
+                }
+                assert!(self.phy_sorted_leaves.len() > 0 || !self.deferred_leaves.is_empty());
+                let Leaf(key, dent) = self.next_leaf();
+                let dent = dent.at_offset(key);
+                // keeps the lowest leaf in memory for `next_scheduled_offset`
+                self.refill_leaves();
+                if self.phy_sorted_leaves.len() == 0 && self.deferred_leaves.is_empty() && self.spill_pending() == 0 {
+                    self.set_phase(Phase::DirWalk);
+                }
+                self.verify_order(key, &dent);
+                return Some(self.revalidate(dent))
+            }
 
-        None
+            // book the final stretch, the clock starts again if more gets queued
+            self.set_phase(Phase::DirWalk);
+            self.phase_started = None;
+            return None;
+        }
     }
 
 }
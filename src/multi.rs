@@ -0,0 +1,63 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use {Entry, ToScan, WalkError};
+
+/// Index of a walker within a `MultiScan`, in the order they were added
+pub type DeviceId = usize;
+
+/// Takes turns between several walkers, typically one per disk since physical ordering across
+/// disks is meaningless. Interleaving keeps the prefetch of every disk going instead of draining them one by one.
+pub struct MultiScan<T = ()> {
+    scans: Vec<Option<ToScan<T>>>,
+    next: usize,
+}
+
+impl<T: Clone> MultiScan<T> {
+    pub fn new() -> MultiScan<T> {
+        MultiScan {
+            scans: vec![],
+            next: 0
+        }
+    }
+
+    /// Returns the id the walker's entries will be tagged with
+    pub fn add(&mut self, scan: ToScan<T>) -> DeviceId {
+        self.scans.push(Some(scan));
+        self.scans.len() - 1
+    }
+}
+
+impl<T: Clone> Default for MultiScan<T> {
+    fn default() -> MultiScan<T> {
+        MultiScan::new()
+    }
+}
+
+impl<T: Clone> Iterator for MultiScan<T> {
+    type Item = Result<(DeviceId, Entry<T>), WalkError>;
+
+    fn next(&mut self) -> Option<Result<(DeviceId, Entry<T>), WalkError>> {
+        for _ in 0..self.scans.len() {
+            let id = self.next;
+            self.next = (self.next + 1) % self.scans.len();
+
+            let res = match self.scans[id] {
+                Some(ref mut scan) => scan.next(),
+                None => continue
+            };
+
+            match res {
+                Some(r) => return Some(r.map(|e| (id, e))),
+                // drop exhausted walkers to free their buffers early
+                None => self.scans[id] = None
+            }
+        }
+
+        None
+    }
+}
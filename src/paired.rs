@@ -0,0 +1,131 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::vec;
+use {Entry, ToScan, WalkError};
+
+/// Outcome for one relative path of a `PairedScan`
+pub enum DiffEvent<T = ()> {
+    OnlyLeft(Entry<T>),
+    OnlyRight(Entry<T>),
+    Both(Entry<T>, Entry<T>),
+}
+
+/// Walks two trees side by side, e.g. source and destination of a sync, and matches their entries by path
+/// relative to their roots. Each walker keeps its own order, so entries are held back until their counterpart
+/// shows up or the other side is exhausted. How much piles up depends on how much the two physical orders differ,
+/// for two unrelated layouts it can approach the size of the smaller tree.
+/// Roots have to be added before the walkers are handed over. Errors of either side are passed through.
+/// A relative path that shows up more than once on the same side is only matched once, the others are unmatched.
+pub struct PairedScan<T = ()> {
+    left: Side<T>,
+    right: Side<T>,
+    left_next: bool,
+    flushed: Option<vec::IntoIter<DiffEvent<T>>>,
+}
+
+struct Side<T> {
+    scan: Option<ToScan<T>>,
+    roots: Vec<PathBuf>,
+    /// entries still waiting for a match, by relative path
+    pending: HashMap<PathBuf, Entry<T>>,
+}
+
+impl<T: Clone> Side<T> {
+    fn new(scan: ToScan<T>) -> Side<T> {
+        Side {
+            roots: scan.roots().to_vec(),
+            scan: Some(scan),
+            pending: HashMap::new()
+        }
+    }
+
+    /// Paths of roots added by fd are relative already and stay as they are
+    fn relative(&self, p: &Path) -> PathBuf {
+        self.roots.iter().filter_map(|r| p.strip_prefix(r).ok()).next().unwrap_or(p).to_owned()
+    }
+
+    fn next(&mut self) -> Option<Result<Entry<T>, WalkError>> {
+        let res = match self.scan {
+            Some(ref mut scan) => scan.next(),
+            None => return None
+        };
+        if res.is_none() {
+            // release the walker's buffers early
+            self.scan = None;
+        }
+        res
+    }
+}
+
+impl<T: Clone> PairedScan<T> {
+    pub fn new(left: ToScan<T>, right: ToScan<T>) -> PairedScan<T> {
+        PairedScan {
+            left: Side::new(left),
+            right: Side::new(right),
+            left_next: true,
+            flushed: None
+        }
+    }
+
+    /// Entries held back while waiting for their counterpart
+    pub fn buffered(&self) -> usize {
+        self.left.pending.len() + self.right.pending.len()
+    }
+}
+
+impl<T: Clone> Iterator for PairedScan<T> {
+    type Item = Result<DiffEvent<T>, WalkError>;
+
+    fn next(&mut self) -> Option<Result<DiffEvent<T>, WalkError>> {
+        loop {
+            if let Some(ref mut flushed) = self.flushed {
+                return flushed.next().map(Ok);
+            }
+
+            // alternate so that neither side runs far ahead while the other one still has entries
+            let from_left = match (self.left.scan.is_some(), self.right.scan.is_some()) {
+                (true, true) => self.left_next,
+                (true, false) => true,
+                (false, true) => false,
+                (false, false) => {
+                    // whatever is left over has no counterpart
+                    let left = self.left.pending.drain().map(|(_, e)| DiffEvent::OnlyLeft(e));
+                    let right = self.right.pending.drain().map(|(_, e)| DiffEvent::OnlyRight(e));
+                    self.flushed = Some(left.chain(right).collect::<Vec<_>>().into_iter());
+                    continue;
+                }
+            };
+            self.left_next = !from_left;
+
+            let (this, other) = if from_left { (&mut self.left, &mut self.right) } else { (&mut self.right, &mut self.left) };
+
+            let e = match this.next() {
+                Some(Ok(e)) => e,
+                Some(Err(e)) => return Some(Err(e)),
+                None => continue
+            };
+
+            let rel = this.relative(e.path());
+            let event = match other.pending.remove(&rel) {
+                Some(counterpart) if from_left => DiffEvent::Both(e, counterpart),
+                Some(counterpart) => DiffEvent::Both(counterpart, e),
+                None if other.scan.is_none() => if from_left { DiffEvent::OnlyLeft(e) } else { DiffEvent::OnlyRight(e) },
+                None => match this.pending.insert(rel, e) {
+                    // the same relative path twice on one side, e.g. from overlapping roots.
+                    // Only one can be matched, the earlier one is given up
+                    Some(old) if from_left => DiffEvent::OnlyLeft(old),
+                    Some(old) => DiffEvent::OnlyRight(old),
+                    None => continue
+                }
+            };
+            return Some(Ok(event));
+        }
+    }
+}
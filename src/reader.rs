@@ -0,0 +1,68 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use dir;
+use std::collections::VecDeque;
+use std::fs::File;
+use {Entry, ToScan, WalkError};
+
+/// Opens the files of a content ordered walk ahead of the consumer, see `ToScan::into_content_reader`
+pub struct ContentReader<T = ()> {
+    walk: ToScan<T>,
+    window: VecDeque<Result<(Entry<T>, File), WalkError>>,
+    read_ahead: usize,
+}
+
+impl<T: Clone> ContentReader<T> {
+    pub(crate) fn new(walk: ToScan<T>, read_ahead: usize) -> ContentReader<T> {
+        ContentReader {
+            walk: walk,
+            window: VecDeque::new(),
+            read_ahead: ::std::cmp::max(1, read_ahead)
+        }
+    }
+
+    /// The underlying walk, e.g. for its `stats`
+    pub fn walk(&self) -> &ToScan<T> {
+        &self.walk
+    }
+
+    fn fill(&mut self) {
+        while self.window.len() < self.read_ahead {
+            let e = match self.walk.next() {
+                Some(Ok(ref e)) if !e.file_type().is_file() => continue,
+                Some(Ok(e)) => e,
+                Some(Err(e)) => {
+                    self.window.push_back(Err(e));
+                    continue;
+                }
+                None => break
+            };
+
+            let opened = dir::open_file_at(e.base_fd(), e.path(), self.walk.noatime).and_then(|f| {
+                let len = f.metadata()?.len();
+                ::fadvise_willneed(&f, 0, len, self.walk.max_fadvise_chunk);
+                Ok(f)
+            });
+
+            self.window.push_back(match opened {
+                Ok(f) => Ok((e, f)),
+                Err(err) => Err(WalkError::new(e.path.clone(), err))
+            });
+        }
+    }
+}
+
+impl<T: Clone> Iterator for ContentReader<T> {
+    type Item = Result<(Entry<T>, File), WalkError>;
+
+    fn next(&mut self) -> Option<Result<(Entry<T>, File), WalkError>> {
+        // top up before handing out the head so the advise for the rest of the window is already in flight
+        self.fill();
+        self.window.pop_front()
+    }
+}
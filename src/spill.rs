@@ -0,0 +1,193 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Sorted runs of content pass leaves kept on disk and merged back in during the content pass,
+//! see `ToScan::set_spill_dir`.
+
+use std::collections::BinaryHeap;
+use std::ffi::OsStr;
+use std::fs::{self, File, FileType, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use {Entry, Leaf, Order};
+
+static RUN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Records loaded from a run at a time
+const CHUNK: u64 = 4096;
+
+pub struct Spill<T> {
+    pub dir: PathBuf,
+    /// Stands in for the tags of restored entries, spilling is only offered for untagged walks
+    tag: T,
+    /// The few distinct file types, records refer to them by index
+    types: Vec<FileType>,
+    /// Directories of fd roots, which can't be written out as numbers since restored entries have to keep them open
+    bases: Vec<Arc<File>>,
+    runs: Vec<Run>,
+}
+
+struct Run {
+    reader: BufReader<File>,
+    remaining: u64,
+    /// Key of the last record loaded from the run, everything still on disk sorts after it
+    frontier: Option<((u8, u32), u64, u64)>,
+}
+
+impl<T: Clone> Spill<T> {
+    pub fn new(dir: PathBuf, tag: T) -> Spill<T> {
+        Spill {
+            dir: dir,
+            tag: tag,
+            types: vec![],
+            bases: vec![],
+            runs: vec![]
+        }
+    }
+
+    /// Records not yet loaded back
+    pub fn pending(&self) -> usize {
+        self.runs.iter().map(|r| r.remaining as usize).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.runs.clear();
+    }
+
+    /// `leaves` have to be in ascending order. The file is unlinked right away so nothing is left behind if the process dies.
+    pub fn write_run(&mut self, leaves: &[Leaf<T>]) -> io::Result<()> {
+        let name = format!(".platter-walk-{}-{}", process::id(), RUN_COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = self.dir.join(name);
+        let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        fs::remove_file(&path)?;
+
+        {
+            let mut w = BufWriter::new(&mut file);
+            for leaf in leaves {
+                self.write_leaf(&mut w, leaf)?;
+            }
+            w.flush()?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+
+        self.runs.push(Run {
+            reader: BufReader::new(file),
+            remaining: leaves.len() as u64,
+            frontier: None
+        });
+        Ok(())
+    }
+
+    fn write_leaf<W: Write>(&mut self, w: &mut W, leaf: &Leaf<T>) -> io::Result<()> {
+        let e = &leaf.1;
+        let ftype = match self.types.iter().position(|&t| t == e.ftype) {
+            Some(idx) => idx,
+            None => {
+                self.types.push(e.ftype);
+                self.types.len() - 1
+            }
+        };
+        let base = match e.base {
+            Some(ref base) => match self.bases.iter().position(|b| Arc::ptr_eq(b, base)) {
+                Some(idx) => idx as i64,
+                None => {
+                    self.bases.push(base.clone());
+                    self.bases.len() as i64 - 1
+                }
+            },
+            None => -1
+        };
+        let path = e.path.as_os_str().as_bytes();
+
+        w.write_all(&leaf.0.to_le_bytes())?;
+        w.write_all(&e.ino.to_le_bytes())?;
+        w.write_all(&e.dev.to_le_bytes())?;
+        w.write_all(&e.located_bytes.to_le_bytes())?;
+        w.write_all(&(e.depth as u64).to_le_bytes())?;
+        w.write_all(&base.to_le_bytes())?;
+        w.write_all(&[ftype as u8, (e.order == Order::Content) as u8, e.size_bucket])?;
+        // only unplaced files have a rank, spilling is off with a device map
+        w.write_all(&e.device_rank.to_le_bytes())?;
+        w.write_all(&(path.len() as u64).to_le_bytes())?;
+        w.write_all(path)
+    }
+
+    /// Loads every remaining record into `heap`
+    pub fn load_all(&mut self, heap: &mut BinaryHeap<Leaf<T>>, statx_mask: u32) -> io::Result<()> {
+        for run in &mut self.runs {
+            while run.remaining > 0 {
+                heap.push(read_leaf(&mut run.reader, &self.types, &self.bases, &self.tag, statx_mask)?);
+                run.remaining -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads records into `heap` until its lowest leaf sorts before anything still on disk.
+    /// A run that fails to read is abandoned with the rest of its records.
+    pub fn refill(&mut self, heap: &mut BinaryHeap<Leaf<T>>, statx_mask: u32) -> io::Result<()> {
+        loop {
+            let lowest = heap.peek().map(|l| l.key());
+            let run = self.runs.iter_mut().find(|r| r.remaining > 0 && match (r.frontier, lowest) {
+                (Some(frontier), Some(lowest)) => frontier <= lowest,
+                _ => true
+            });
+            let run = match run {
+                Some(run) => run,
+                None => return Ok(())
+            };
+
+            for _ in 0..::std::cmp::min(CHUNK, run.remaining) {
+                match read_leaf(&mut run.reader, &self.types, &self.bases, &self.tag, statx_mask) {
+                    Ok(leaf) => {
+                        run.frontier = Some(leaf.key());
+                        run.remaining -= 1;
+                        heap.push(leaf);
+                    }
+                    Err(e) => {
+                        run.remaining = 0;
+                        return Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn read_leaf<T: Clone>(r: &mut BufReader<File>, types: &[FileType], bases: &[Arc<File>], tag: &T, statx_mask: u32) -> io::Result<Leaf<T>> {
+    let (key, ino, dev, located_bytes) = (read_u64(r)?, read_u64(r)?, read_u64(r)?, read_u64(r)?);
+    let (depth, base) = (read_u64(r)?, read_u64(r)? as i64);
+    let mut flags = [0u8; 3];
+    r.read_exact(&mut flags)?;
+    let mut rank = [0u8; 4];
+    r.read_exact(&mut rank)?;
+    let mut path = vec![0u8; read_u64(r)? as usize];
+    r.read_exact(&mut path)?;
+
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt spill record");
+    let ftype = *types.get(flags[0] as usize).ok_or_else(corrupt)?;
+    let mut e = Entry::with_tag(PathBuf::from(OsStr::from_bytes(&path)), ftype, ino, vec![], tag.clone());
+    e.depth = depth as usize;
+    e.dev = dev;
+    e.base = if base < 0 { None } else { Some(bases.get(base as usize).ok_or_else(corrupt)?.clone()) };
+    e.size_bucket = flags[2];
+    e.device_rank = u32::from_le_bytes(rank);
+    e.located_bytes = located_bytes;
+    let order = if flags[1] == 1 { Order::Content } else { Order::Inode };
+    // statx results aren't spilled, they're fetched again
+    Ok(Leaf(key, e.with_order(order).fetch_statx(statx_mask)))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
@@ -0,0 +1,315 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Scheduling tests against the synthetic tree of `FakeFs`
+
+use btrfs::linux::FileExtent;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, FileType};
+use std::path::{Path, PathBuf};
+use {DiffEvent, Direction, Entry, FakeFs, Order, PairedScan, SizePriority, ToScan, UnknownOffsetPolicy, WalkError};
+
+type Tree = BTreeMap<PathBuf, (FileType, u64, Vec<FileExtent>)>;
+
+/// `FileType` can't be constructed, so samples are taken from the real filesystem
+fn types() -> (FileType, FileType) {
+    let dir = fs::metadata(env::temp_dir()).unwrap().file_type();
+    let file = fs::metadata(env::current_exe().unwrap()).unwrap().file_type();
+    (dir, file)
+}
+
+fn extent(physical: u64) -> Vec<FileExtent> {
+    vec![FileExtent { logical: 0, physical: physical, length: 4096 }]
+}
+
+/// Two directories whose files are laid out interleaved, with inode and physical order disagreeing,
+/// plus a fragmented file and one without extents
+fn tree() -> Tree {
+    let (d, f) = types();
+    let mut t = Tree::new();
+    t.insert(PathBuf::from("/r"), (d, 1, extent(30000)));
+    t.insert(PathBuf::from("/r/a"), (d, 2, extent(50000)));
+    t.insert(PathBuf::from("/r/b"), (d, 3, extent(10000)));
+    for i in 0..20u64 {
+        t.insert(PathBuf::from(format!("/r/a/f{}", i)), (f, 100 + i, extent((i * 7919) % 100000 + 1)));
+        t.insert(PathBuf::from(format!("/r/b/g{}", i)), (f, 200 + (i * 13) % 20, extent((i * 4099) % 100000 + 1)));
+    }
+    t.insert(PathBuf::from("/r/b/frag"), (f, 300, vec![
+        FileExtent { logical: 0, physical: 90000, length: 10 },
+        FileExtent { logical: 10, physical: 5, length: 10 }
+    ]));
+    t.insert(PathBuf::from("/r/b/empty"), (f, 301, vec![]));
+    t
+}
+
+/// Walks `tree()` with the ordering contract checked by the walker itself
+fn walk<F: FnOnce(&mut ToScan)>(order: Order, cfg: F) -> Vec<Entry> {
+    let mut scan = ToScan::with_fake_fs(FakeFs::new(tree()));
+    scan.set_order(order);
+    scan.set_verify_ordering(true);
+    cfg(&mut scan);
+    scan.add_root(PathBuf::from("/r")).unwrap();
+    scan.map(|e| e.unwrap()).collect()
+}
+
+fn paths(entries: &[Entry]) -> Vec<&Path> {
+    entries.iter().map(|e| e.path()).collect()
+}
+
+/// Everything below the root, each exactly once
+fn assert_complete(entries: &[Entry]) {
+    assert_complete_paths(paths(entries));
+}
+
+fn assert_complete_paths(mut yielded: Vec<&Path>) {
+    yielded.sort();
+    let expected: Vec<_> = tree().keys().filter(|p| p.as_path() != Path::new("/r")).cloned().collect();
+    assert_eq!(yielded, expected.iter().map(|p| p.as_path()).collect::<Vec<_>>());
+}
+
+fn assert_ascending<K: Ord + ::std::fmt::Debug>(keys: Vec<K>) {
+    for pair in keys.windows(2) {
+        assert!(pair[0] <= pair[1], "{:?} yielded before {:?}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn schedule_by_lowest_extent() {
+    let mut scan = ToScan::new();
+    // extent maps are in logical order, here the tail of the file comes first on disk
+    scan.schedule(Entry::new(PathBuf::from("frag"), types().1, 1, vec![
+        FileExtent { logical: 0, physical: 90000, length: 10 },
+        FileExtent { logical: 10, physical: 5, length: 10 }
+    ]));
+    assert_eq!(scan.phy_sorted.keys().cloned().collect::<Vec<_>>(), [5]);
+}
+
+#[test]
+fn dentries() {
+    let entries = walk(Order::Dentries, |_| {});
+    assert_complete(&entries);
+    // b's contents are physically first, directories are visited in that order
+    let first_a = entries.iter().position(|e| e.path().starts_with("/r/a/")).unwrap();
+    assert!(entries[..first_a].iter().filter(|e| e.depth() == 2).all(|e| e.path().starts_with("/r/b/")));
+}
+
+#[test]
+fn readdir_natural() {
+    let entries = walk(Order::ReaddirNatural, |_| {});
+    assert_complete(&entries);
+    // breadth-first in discovery order
+    assert_eq!(paths(&entries)[..2], [Path::new("/r/a"), Path::new("/r/b")]);
+    assert!(entries[2..22].iter().all(|e| e.path().starts_with("/r/a/")));
+}
+
+#[test]
+fn inode() {
+    let entries = walk(Order::Inode, |s| { s.set_batchsize(1000); });
+    assert_complete(&entries);
+    assert_ascending(entries.iter().map(|e| e.ino()).collect());
+}
+
+#[test]
+fn inode_small_batches() {
+    assert_complete(&walk(Order::Inode, |s| { s.set_batchsize(7); }));
+}
+
+#[test]
+fn content() {
+    let entries = walk(Order::Content, |s| { s.set_batchsize(1000); });
+    assert_complete(&entries);
+    // without extents there's nothing to sort by, those are placed at offset 0
+    assert_eq!(entries[0].path(), Path::new("/r/b/empty"));
+    assert_eq!(entries[0].effective_order(), Order::Inode);
+    // the fragmented file is placed by its lowest extent
+    assert_eq!(entries[3].path(), Path::new("/r/b/frag"));
+    assert_ascending(entries.iter().map(|e| e.offset).collect());
+}
+
+#[test]
+fn content_small_batches() {
+    assert_complete(&walk(Order::Content, |s| { s.set_batchsize(7); }));
+}
+
+#[test]
+fn content_single_sweep() {
+    let entries = walk(Order::Content, |s| s.set_single_sweep(true));
+    assert_complete(&entries);
+    let located: Vec<_> = entries.iter().filter(|e| e.file_type().is_file() && !e.extents.is_empty()).collect();
+    assert!(located.iter().all(|e| e.effective_order() == Order::Content));
+    // placed by their own lowest extent, not the cursor they were reached at
+    assert!(located.iter().all(|e| Some(e.offset) == e.extents.iter().map(|x| x.physical).min()));
+}
+
+#[test]
+fn readiness() {
+    assert_complete(&walk(Order::Readiness, |_| {}));
+}
+
+#[test]
+fn dirs_then_files() {
+    let entries = walk(Order::DirsThenFiles, |_| {});
+    assert_complete(&entries);
+    let first_file = entries.iter().position(|e| !e.file_type().is_dir()).unwrap();
+    assert!(entries[first_file..].iter().all(|e| !e.file_type().is_dir()));
+    assert_ascending(entries[first_file..].iter().map(|e| e.offset).collect());
+}
+
+#[test]
+fn rotational_optimal_without_geometry() {
+    let entries = walk(Order::RotationalOptimal, |s| { s.set_batchsize(1000); });
+    assert_complete(&entries);
+    assert_ascending(entries.iter().map(|e| e.offset).collect());
+}
+
+/// The root exactly once, returns its position. Everything else has to be there too.
+fn root_position(entries: &[Entry]) -> usize {
+    let root: Vec<_> = entries.iter().enumerate().filter(|&(_, e)| e.path() == Path::new("/r")).map(|(i, _)| i).collect();
+    assert_eq!(root.len(), 1, "root yielded {} times", root.len());
+    let mut rest = paths(entries);
+    rest.remove(root[0]);
+    assert_complete_paths(rest);
+    root[0]
+}
+
+#[test]
+fn yield_root_dentries() {
+    let entries = walk(Order::Dentries, |s| s.set_yield_root(true));
+    // emitted when it's opened, before any of its children
+    assert_eq!(root_position(&entries), 0);
+}
+
+#[test]
+fn yield_root_inode() {
+    let entries = walk(Order::Inode, |s| { s.set_batchsize(1000); s.set_yield_root(true); });
+    // sorted into the batch by its inode like any child
+    assert_eq!(root_position(&entries), 0);
+    assert_ascending(entries.iter().map(|e| e.ino()).collect());
+}
+
+#[test]
+fn yield_root_content() {
+    let entries = walk(Order::Content, |s| { s.set_batchsize(1000); s.set_yield_root(true); });
+    // placed by the extents of its directory blocks
+    let pos = root_position(&entries);
+    assert_eq!(entries[pos].offset, 30000);
+    assert!(pos > 0 && pos < entries.len() - 1);
+    assert_ascending(entries.iter().map(|e| e.offset).collect());
+}
+
+/// Files without extents before or after every sweep, whatever the sweeps are split by
+fn assert_unplaced_at_ends<F: Fn(&mut ToScan)>(cfg: F) {
+    let first = walk(Order::Content, |s| { s.set_batchsize(1000); s.set_unknown_offset_policy(UnknownOffsetPolicy::First); cfg(s); });
+    assert_complete(&first);
+    assert_eq!(first[0].path(), Path::new("/r/b/empty"));
+    let last = walk(Order::Content, |s| { s.set_batchsize(1000); s.set_unknown_offset_policy(UnknownOffsetPolicy::Last); cfg(s); });
+    assert_complete(&last);
+    assert_eq!(last[last.len() - 1].path(), Path::new("/r/b/empty"));
+}
+
+#[test]
+fn unplaced_with_device_map() {
+    assert_unplaced_at_ends(|s| s.set_device_map(Box::new(|physical| if physical < 50000 {
+        (PathBuf::from("/dev/a"), physical)
+    } else {
+        (PathBuf::from("/dev/b"), physical - 50000)
+    })));
+}
+
+#[test]
+fn unplaced_with_size_priority() {
+    assert_unplaced_at_ends(|s| s.set_size_priority(SizePriority::LargeFirst));
+    assert_unplaced_at_ends(|s| s.set_size_priority(SizePriority::SmallFirst));
+}
+
+#[test]
+fn readdir_error_keeps_listing() {
+    let mut fs = FakeFs::new(tree());
+    // sorts before all of its siblings
+    fs.add_bad_entry(PathBuf::from("/r/b/bad"));
+    let mut scan = ToScan::with_fake_fs(fs);
+    scan.add_root(PathBuf::from("/r")).unwrap();
+    let (entries, errors): (Vec<_>, Vec<_>) = scan.partition(|r| r.is_ok());
+    assert_eq!(errors.len(), 1);
+    let err = errors.into_iter().next().unwrap().err().unwrap();
+    assert_eq!(err.path(), Path::new("/r/b"));
+    assert_complete(&entries.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>());
+}
+
+#[test]
+fn paired_duplicate_paths() {
+    let mut left = ToScan::with_fake_fs(FakeFs::new(tree()));
+    // everything shows up twice on the left
+    left.add_root(PathBuf::from("/r")).unwrap();
+    left.add_root(PathBuf::from("/r")).unwrap();
+    // yields the directories last, after both copies of them are waiting on the left
+    let mut right = ToScan::with_fake_fs(FakeFs::new(tree()));
+    right.set_order(Order::Inode);
+    right.set_batchsize(1000);
+    right.set_inode_direction(Direction::Descending);
+    right.add_root(PathBuf::from("/r")).unwrap();
+    let (mut both, mut only_left) = (vec![], vec![]);
+    for event in PairedScan::new(left, right) {
+        match event.unwrap() {
+            DiffEvent::Both(l, r) => {
+                assert_eq!(l.path(), r.path());
+                both.push(l);
+            }
+            DiffEvent::OnlyLeft(l) => only_left.push(l),
+            DiffEvent::OnlyRight(r) => panic!("{:?} has a counterpart", r.path())
+        }
+    }
+    // each matched once, the duplicate is reported rather than dropped
+    assert_complete(&both);
+    assert_complete(&only_left);
+}
+
+/// `tree()` plus a directory that turns into a file before it is read, visited between /r/b and /r/a
+/// Errors come with the number of entries yielded before them
+fn walk_replaced_dir(skip: bool) -> (Vec<Entry>, Vec<(usize, WalkError)>, u64) {
+    let mut t = tree();
+    t.insert(PathBuf::from("/r/c"), (types().0, 4, extent(30000)));
+    let mut fs = FakeFs::new(t);
+    fs.add_replaced_dir(PathBuf::from("/r/c"));
+    let mut scan = ToScan::with_fake_fs(fs);
+    scan.set_verify_ordering(true);
+    scan.set_skip_replaced_dirs(skip);
+    scan.add_root(PathBuf::from("/r")).unwrap();
+    let (mut entries, mut errors) = (vec![], vec![]);
+    for r in scan.by_ref() {
+        match r {
+            Ok(e) => entries.push(e),
+            Err(e) => errors.push((entries.len(), e))
+        }
+    }
+    // still listed by its parent
+    let c = entries.iter().position(|e| e.path() == Path::new("/r/c")).unwrap();
+    entries.remove(c);
+    assert_complete(&entries);
+    (entries, errors.into_iter().map(|(i, e)| (if i > c { i - 1 } else { i }, e)).collect(), scan.stats().replaced_dirs)
+}
+
+#[test]
+fn replaced_dir_error() {
+    let (entries, errors, replaced) = walk_replaced_dir(false);
+    assert_eq!(errors.len(), 1);
+    let (at, ref err) = errors[0];
+    assert_eq!(err.path(), Path::new("/r/c"));
+    assert_eq!(err.io_error().raw_os_error(), Some(::libc::ENOTDIR));
+    assert_eq!(replaced, 0);
+    // /r/a is only read after /r/c
+    assert!(entries[at..].iter().all(|e| e.path().starts_with("/r/a/")));
+    assert_eq!(entries[at..].len(), 20);
+}
+
+#[test]
+fn replaced_dir_skipped() {
+    let (_, errors, replaced) = walk_replaced_dir(true);
+    assert!(errors.is_empty());
+    assert_eq!(replaced, 1);
+}
@@ -0,0 +1,154 @@
+//   platter-walk
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::vec;
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum TraceEvent {
+    /// An entry was yielded with the given schedule key, see `ToScan::plan`
+    Yield { path: PathBuf, key: u64 },
+    /// A coalesced run was advised on a device
+    Advise { device: PathBuf, offset: u64, len: u64 },
+}
+
+/// The scheduling decisions of a walk as recorded with `ToScan::record`
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct WalkTrace {
+    events: Vec<TraceEvent>,
+    yielded: u64,
+}
+
+impl WalkTrace {
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    pub(crate) fn push(&mut self, event: TraceEvent) {
+        if let TraceEvent::Yield{..} = event {
+            self.yielded += 1;
+        }
+        self.events.push(event);
+    }
+
+    /// Number of entries yielded so far
+    pub fn yielded(&self) -> u64 {
+        self.yielded
+    }
+
+    /// Records are NUL-terminated since paths can contain anything else
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for e in &self.events {
+            match *e {
+                TraceEvent::Yield { ref path, key } => {
+                    write!(w, "y {} ", key)?;
+                    w.write_all(path.as_os_str().as_bytes())?;
+                }
+                TraceEvent::Advise { ref device, offset, len } => {
+                    write!(w, "a {} {} ", offset, len)?;
+                    w.write_all(device.as_os_str().as_bytes())?;
+                }
+            }
+            w.write_all(b"\0")?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: BufRead>(r: &mut R) -> io::Result<WalkTrace> {
+        let mut trace = WalkTrace::default();
+        let mut record = vec![];
+
+        loop {
+            record.clear();
+            if r.read_until(0, &mut record)? == 0 {
+                break;
+            }
+            if record.last() == Some(&0) {
+                record.pop();
+            }
+
+            // the path is always the last field and may itself contain spaces
+            let event = if record.starts_with(b"y ") {
+                let mut fields = record[2..].splitn(2, |&b| b == b' ');
+                match (fields.next(), fields.next()) {
+                    (Some(key), Some(path)) => TraceEvent::Yield {
+                        path: PathBuf::from(OsStr::from_bytes(path)),
+                        key: parse_num(key)?
+                    },
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed trace record"))
+                }
+            } else {
+                let mut fields = record.splitn(4, |&b| b == b' ');
+                match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                    (Some(b"a"), Some(offset), Some(len), Some(device)) => TraceEvent::Advise {
+                        device: PathBuf::from(OsStr::from_bytes(device)),
+                        offset: parse_num(offset)?,
+                        len: parse_num(len)?
+                    },
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed trace record"))
+                }
+            };
+            trace.push(event);
+        }
+
+        Ok(trace)
+    }
+
+    /// Re-issues the recorded advises at the same points relative to the yielded entries without walking the filesystem.
+    /// Yields the recorded `(path, key)` pairs.
+    pub fn replay(self) -> Replay {
+        Replay {
+            events: self.events.into_iter(),
+            devices: HashMap::new(),
+            max_chunk: 32*1024*1024
+        }
+    }
+}
+
+fn parse_num(field: &[u8]) -> io::Result<u64> {
+    ::std::str::from_utf8(field).ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed number in trace record"))
+}
+
+pub struct Replay {
+    events: vec::IntoIter<TraceEvent>,
+    devices: HashMap<PathBuf, Option<File>>,
+    max_chunk: u64,
+}
+
+impl Replay {
+    /// See `ToScan::set_max_fadvise_chunk`, the trace only records the coalesced runs
+    pub fn set_max_fadvise_chunk(&mut self, bytes: u64) {
+        self.max_chunk = bytes;
+    }
+}
+
+impl Iterator for Replay {
+    type Item = (PathBuf, u64);
+
+    fn next(&mut self) -> Option<(PathBuf, u64)> {
+        for e in &mut self.events {
+            match e {
+                TraceEvent::Yield { path, key } => return Some((path, key)),
+                TraceEvent::Advise { device, offset, len } => {
+                    // devices that can't be opened are skipped, just like during the recorded walk
+                    let dev = self.devices.entry(device.clone()).or_insert_with(|| File::open(&device).ok());
+                    if let Some(ref f) = *dev {
+                        ::fadvise_willneed(f, offset, offset + len, self.max_chunk);
+                    }
+                }
+            }
+        }
+        None
+    }
+}